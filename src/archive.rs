@@ -0,0 +1,449 @@
+use crate::command::{ArchiveArgs, ArchiveCommands};
+use crate::detect::{self, FileKind};
+use crate::file_handler::{format_size, zip_time_to_system_time};
+use anyhow::anyhow;
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use chrono::{DateTime, Local};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+use zip::ZipArchive;
+
+/// Compression applied on top of a tar archive or a single file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+/// What a file on disk is, or what an output name asks for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Zip,
+    Tar(Codec),
+    /// A single compressed file, e.g. `app.log.gz`.
+    Stream(Codec),
+}
+
+impl Codec {
+    /// Recognises a codec by the magic bytes at the start of a file.
+    pub fn from_magic(head: &[u8]) -> Codec {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Codec::Gzip
+        } else if head.starts_with(b"BZh") && head.get(3).is_some_and(|b| (b'1'..=b'9').contains(b))
+        {
+            Codec::Bzip2
+        } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Codec::Xz
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else {
+            Codec::None
+        }
+    }
+
+    /// File name suffixes of a single compressed file, longest first.
+    pub fn suffixes(self) -> &'static [&'static str] {
+        match self {
+            Codec::None => &[],
+            Codec::Gzip => &[".gz"],
+            Codec::Bzip2 => &[".bz2"],
+            Codec::Xz => &[".xz"],
+            Codec::Zstd => &[".zstd", ".zst"],
+        }
+    }
+
+    pub fn level_range(self) -> Option<RangeInclusive<i32>> {
+        match self {
+            Codec::None => None,
+            Codec::Gzip | Codec::Xz => Some(0..=9),
+            Codec::Bzip2 => Some(1..=9),
+            Codec::Zstd => Some(1..=22),
+        }
+    }
+
+    /// Wraps `reader` so it yields decompressed bytes. Concatenated
+    /// streams (as produced by log rotation) are read as one.
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Codec::None => Box::new(reader),
+            Codec::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Codec::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+            Codec::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+            Codec::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        })
+    }
+
+    pub fn encoder<W: Write>(self, writer: W, level: Option<i32>) -> anyhow::Result<Encoder<W>> {
+        if let Some(level) = level {
+            match self.level_range() {
+                Some(range) if range.contains(&level) => {}
+                Some(range) => {
+                    return Err(anyhow!(
+                        "Error: Level {} is out of range {}-{} for {:?}.",
+                        level,
+                        range.start(),
+                        range.end(),
+                        self
+                    ))
+                }
+                None => return Err(anyhow!("Error: Plain tar does not take a level.")),
+            }
+        }
+
+        Ok(match self {
+            Codec::None => Encoder::Plain(writer),
+            Codec::Gzip => Encoder::Gzip(GzEncoder::new(
+                writer,
+                flate2::Compression::new(level.unwrap_or(6) as u32),
+            )),
+            Codec::Bzip2 => Encoder::Bzip2(BzEncoder::new(
+                writer,
+                bzip2::Compression::new(level.unwrap_or(6) as u32),
+            )),
+            Codec::Xz => Encoder::Xz(XzEncoder::new(writer, level.unwrap_or(6) as u32)),
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, level.unwrap_or(3))?),
+        })
+    }
+}
+
+/// A writer compressing with one of the supported codecs. `finish` must be
+/// called to write the codec's trailer.
+pub enum Encoder<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Bzip2(BzEncoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(w) => Ok(w),
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Bzip2(e) => e.finish(),
+            Encoder::Xz(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(w) => w.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Bzip2(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(w) => w.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Bzip2(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Picks the format an output file name asks for, e.g. `.tar.zst` or `.bz2`.
+pub fn format_from_name(name: &str) -> Option<Format> {
+    let name = name.to_lowercase();
+    let formats = [
+        (".zip", Format::Zip),
+        (".tar", Format::Tar(Codec::None)),
+        (".tar.gz", Format::Tar(Codec::Gzip)),
+        (".tgz", Format::Tar(Codec::Gzip)),
+        (".tar.bz2", Format::Tar(Codec::Bzip2)),
+        (".tbz2", Format::Tar(Codec::Bzip2)),
+        (".tbz", Format::Tar(Codec::Bzip2)),
+        (".tar.xz", Format::Tar(Codec::Xz)),
+        (".txz", Format::Tar(Codec::Xz)),
+        (".tar.zst", Format::Tar(Codec::Zstd)),
+        (".tzst", Format::Tar(Codec::Zstd)),
+        (".gz", Format::Stream(Codec::Gzip)),
+        (".bz2", Format::Stream(Codec::Bzip2)),
+        (".xz", Format::Stream(Codec::Xz)),
+        (".zst", Format::Stream(Codec::Zstd)),
+        (".zstd", Format::Stream(Codec::Zstd)),
+    ];
+
+    // Longest suffix wins, so `.tar.gz` is not taken for a plain `.gz`.
+    formats
+        .iter()
+        .filter(|(suffix, _)| name.ends_with(suffix))
+        .max_by_key(|(suffix, _)| suffix.len())
+        .map(|(_, format)| *format)
+}
+
+/// Works out what a file is from its content, looking through a compression
+/// layer to tell a compressed tar from a single compressed file.
+pub fn detect_format(path: &Path) -> anyhow::Result<Format> {
+    let identity = detect::identify(path)?;
+
+    Ok(match identity.kind {
+        FileKind::Zip => Format::Zip,
+        FileKind::Tar => Format::Tar(Codec::None),
+        FileKind::Compressed(codec) if identity.inner == Some(FileKind::Tar) => Format::Tar(codec),
+        FileKind::Compressed(codec) => Format::Stream(codec),
+        _ => Format::Stream(Codec::None),
+    })
+}
+
+/// Opens `path` and undoes `codec`, buffered.
+pub fn open_decoded(path: &Path, codec: Codec) -> anyhow::Result<Box<dyn Read>> {
+    Ok(codec.decoder(BufReader::new(File::open(path)?))?)
+}
+
+/// One entry of an archive as shown by `archive list`.
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// Not known for tar.gz, where the whole stream is compressed at once.
+    pub compressed_size: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+impl ArchiveEntry {
+    pub fn ratio(&self) -> Option<f64> {
+        match self.compressed_size {
+            Some(compressed) if self.size > 0 => Some(compressed as f64 / self.size as f64),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "is_dir": self.is_dir,
+            "size": self.size,
+            "compressed_size": self.compressed_size,
+            "ratio": self.ratio(),
+            "modified": self.modified.map(|t| DateTime::<Local>::from(t).to_rfc3339()),
+        })
+    }
+}
+
+pub fn run_archive(args: &ArchiveArgs) -> anyhow::Result<()> {
+    match &args.command {
+        ArchiveCommands::List {
+            path,
+            json,
+            human_readable,
+        } => {
+            let entries = list_entries(path)?;
+            if *json {
+                print_json(path, &entries)
+            } else {
+                print_table(&entries, *human_readable);
+                Ok(())
+            }
+        }
+        ArchiveCommands::Test { path } => {
+            let failures = test_archive(path)?;
+            if failures == 0 {
+                println!("No errors detected in {}", path.display());
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "Error: {} entries failed the check in {}",
+                    failures,
+                    path.display()
+                ))
+            }
+        }
+    }
+}
+
+/// Like `detect_format`, but only accepts archives with entries.
+fn detect_archive(path: &Path) -> anyhow::Result<Format> {
+    match detect_format(path)? {
+        Format::Stream(_) => Err(anyhow!(
+            "Error: {} is not a zip or tar archive.",
+            path.display()
+        )),
+        format => Ok(format),
+    }
+}
+
+pub fn list_entries(path: &Path) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+
+    match detect_archive(path)? {
+        Format::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let file = archive.by_index_raw(i)?;
+                entries.push(ArchiveEntry {
+                    name: file.name().to_string(),
+                    is_dir: file.is_dir(),
+                    size: file.size(),
+                    compressed_size: Some(file.compressed_size()),
+                    modified: zip_time_to_system_time(file.last_modified()),
+                });
+            }
+        }
+        Format::Tar(codec) => {
+            let mut archive = tar::Archive::new(open_decoded(path, codec)?);
+            for entry in archive.entries()? {
+                let entry = entry?;
+                let header = entry.header();
+                let is_dir = header.entry_type().is_dir();
+                let mut name = entry.path()?.to_string_lossy().to_string();
+                // Match zip, which marks directories with a trailing slash.
+                if is_dir && !name.ends_with('/') {
+                    name.push('/');
+                }
+                entries.push(ArchiveEntry {
+                    name,
+                    is_dir,
+                    size: header.size()?,
+                    compressed_size: None,
+                    modified: header
+                        .mtime()
+                        .ok()
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                });
+            }
+        }
+        Format::Stream(_) => unreachable!("detect_archive rejects single files"),
+    }
+
+    Ok(entries)
+}
+
+/// Reads every entry to the end so zip CRCs, tar header checksums and the
+/// compression layer's checksums are all verified. Returns how many entries failed.
+pub fn test_archive(path: &Path) -> anyhow::Result<usize> {
+    let mut failures = 0;
+
+    match detect_archive(path)? {
+        Format::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i)?;
+                let name = file.name().to_string();
+                match io::copy(&mut file, &mut io::sink()) {
+                    Ok(_) => println!("OK      {}", name),
+                    Err(e) => {
+                        println!("FAILED  {}: {}", name, e);
+                        failures += 1;
+                    }
+                }
+            }
+        }
+        Format::Tar(codec) => {
+            let mut decoder = open_decoded(path, codec)?;
+            {
+                let mut archive = tar::Archive::new(&mut decoder);
+                for entry in archive.entries()? {
+                    let mut entry = match entry {
+                        Ok(entry) => entry,
+                        Err(e) => {
+                            // A broken header leaves no way to find the next entry.
+                            println!("FAILED  <header>: {}", e);
+                            return Ok(failures + 1);
+                        }
+                    };
+                    let name = entry.path()?.to_string_lossy().to_string();
+                    match io::copy(&mut entry, &mut io::sink()) {
+                        Ok(_) => println!("OK      {}", name),
+                        Err(e) => {
+                            println!("FAILED  {}: {}", name, e);
+                            failures += 1;
+                        }
+                    }
+                }
+            }
+
+            // Codec checksums are only verified once the stream is read to its end.
+            if let Err(e) = io::copy(&mut decoder, &mut io::sink()) {
+                println!("FAILED  <{:?} stream>: {}", codec, e);
+                failures += 1;
+            }
+        }
+        Format::Stream(_) => unreachable!("detect_archive rejects single files"),
+    }
+
+    Ok(failures)
+}
+
+fn print_table(entries: &[ArchiveEntry], human_readable: bool) {
+    println!(
+        "{:<12} {:<12} {:<7} {:<20} Name",
+        "Size", "Compressed", "Ratio", "Modified"
+    );
+    println!("{:-<12} {:-<12} {:-<7} {:-<20} {:-<4}", "", "", "", "", "");
+
+    let mut total_size = 0;
+    let mut total_compressed = 0;
+
+    for entry in entries {
+        total_size += entry.size;
+        total_compressed += entry.compressed_size.unwrap_or(0);
+
+        let compressed = entry
+            .compressed_size
+            .map(|c| format_size(c, human_readable))
+            .unwrap_or_else(|| "-".to_string());
+        let ratio = entry
+            .ratio()
+            .map(|r| format!("{:.1}%", r * 100.0))
+            .unwrap_or_else(|| "-".to_string());
+        let modified = entry
+            .modified
+            .map(|t| {
+                DateTime::<Local>::from(t)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        println!(
+            "{:<12} {:<12} {:<7} {:<20} {}",
+            format_size(entry.size, human_readable),
+            compressed,
+            ratio,
+            modified,
+            entry.name
+        );
+    }
+
+    println!(
+        "{} entries, {} uncompressed, {} compressed",
+        entries.len(),
+        format_size(total_size, human_readable),
+        if entries.iter().all(|e| e.compressed_size.is_some()) {
+            format_size(total_compressed, human_readable)
+        } else {
+            "-".to_string()
+        }
+    );
+}
+
+fn print_json(path: &Path, entries: &[ArchiveEntry]) -> anyhow::Result<()> {
+    let output = json!({
+        "archive": path.display().to_string(),
+        "entries": entries.iter().map(ArchiveEntry::to_json).collect::<Vec<_>>(),
+    });
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
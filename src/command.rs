@@ -1,4 +1,4 @@
-use clap::{arg, ArgAction, Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -37,10 +37,21 @@ pub enum Commands {
     #[command(aliases = ["rm"])]
     Remove(RemoveArgs),
 
+    /// Manage files removed to the trash
+    Trash(TrashArgs),
+
     /// Move file or dic
     #[command(aliases = ["mv"])]
     Move(MoveArgs),
 
+    /// Undo recorded operations, the last one by default
+    ///
+    /// `remove --permanent` is not recorded: what it deletes cannot be undone.
+    Undo(UndoArgs),
+
+    /// List the operations that changed files
+    History(HistoryArgs),
+
     /// read table csv.
     #[command(aliases = ["csv-table"])]
     ReadTableCSV(ReadTableCsvArgs),
@@ -49,19 +60,19 @@ pub enum Commands {
     #[command(aliases = ["cp"])]
     Copy(CopyArgs),
 
-    /// Decompress file  .zip
-    #[command(aliases = ["dc-zip"])]
-    DecompressZip(DecompressArgs),
+    /// Decompress .zip, .tar[.gz|.bz2|.xz|.zst] or a single .gz/.bz2/.xz/.zst file
+    #[command(aliases = ["dc", "decompress-zip", "dc-zip", "decompress-gz", "dc-gz"])]
+    Decompress(DecompressArgs),
 
-    /// Decompress file  .gz
-    #[command(aliases = ["dc-gz"])]
-    DecompressGz(DecompressArgs),
+    /// List or verify the contents of a .zip or .tar archive
+    Archive(ArchiveArgs),
 
-    /// compress files zip
-    CompressZip(CompressArgs),
+    /// Tell what files are from their content
+    Identify(IdentifyArgs),
 
-    /// compress files .gz
-    CompressGz(CompressArgsGz),
+    /// Compress files; the output extension picks the format
+    #[command(aliases = ["compress-zip", "compress-gz"])]
+    Compress(CompressArgs),
 
     /// Convert csv to json
     CsvToJson(CsvToJsonArgs),
@@ -88,27 +99,139 @@ pub struct ListArgs {
 
 #[derive(Parser)]
 pub struct SearchArgs {
-    pub(crate) path: String,
-    pub(crate) pattern: String,
+    /// Files, directories or globs to search (default: the current directory),
+    /// then PATTERN unless -e or -f gives the patterns
+    #[arg(value_name = "PATH... PATTERN", required_unless_present_any = ["regexp", "patterns_file"])]
+    pub(crate) inputs: Vec<String>,
+    /// Also search for this pattern (repeatable)
+    #[arg(short = 'e', long = "regexp", value_name = "PATTERN")]
+    pub(crate) regexp: Vec<String>,
+    /// Read patterns from FILE, one per line
+    #[arg(short = 'f', long = "file", value_name = "FILE")]
+    pub(crate) patterns_file: Option<PathBuf>,
+    /// Treat patterns as literal strings, not regexes
+    #[arg(short = 'F', long)]
+    pub(crate) fixed_strings: bool,
+    /// Only match whole words
+    #[arg(short = 'w', long)]
+    pub(crate) word_regexp: bool,
+    /// Only match whole lines
+    #[arg(short = 'x', long, conflicts_with = "word_regexp")]
+    pub(crate) line_regexp: bool,
     #[arg(short = 'i', long, action = ArgAction::SetTrue, default_value_t = false)]
     pub(crate) case_insensitive: bool,
     #[arg(short = 'H', long, action = ArgAction::SetTrue, default_value_t = false)]
     pub(crate) hidden: bool,
     #[arg(short, long,action = ArgAction::SetTrue, default_value_t = true)]
     pub(crate) color: bool,
+    /// Only search files matching this glob, or skip them with a leading `!` (repeatable)
+    #[arg(short = 'g', long, value_name = "GLOB")]
+    pub(crate) glob: Vec<String>,
+    /// Like --glob, ignoring case
+    #[arg(long, value_name = "GLOB")]
+    pub(crate) iglob: Vec<String>,
+    /// Only search files of this type, e.g. rust, log or csv (repeatable)
+    #[arg(short = 't', long = "type", value_name = "TYPE")]
+    pub(crate) file_type: Vec<String>,
+    /// Skip what .gitignore/.ignore files (and git's excludes) ignore
+    #[arg(long)]
+    pub(crate) respect_gitignore: bool,
+    /// Show NUM lines after each match
+    #[arg(short = 'A', long, value_name = "NUM")]
+    pub(crate) after_context: Option<usize>,
+    /// Show NUM lines before each match
+    #[arg(short = 'B', long, value_name = "NUM")]
+    pub(crate) before_context: Option<usize>,
+    /// Show NUM lines before and after each match
+    #[arg(short = 'C', long, value_name = "NUM")]
+    pub(crate) context: Option<usize>,
+    /// Stop reading a file after NUM matching lines
+    #[arg(short = 'm', long, value_name = "NUM")]
+    pub(crate) max_count: Option<u64>,
+    /// Print only the names of files with a match
+    #[arg(short = 'l', long, conflicts_with_all = ["files_without_match", "count"])]
+    pub(crate) files_with_matches: bool,
+    /// Print only the names of files without a match
+    #[arg(short = 'L', long, conflicts_with = "count")]
+    pub(crate) files_without_match: bool,
+    /// Print the number of matching lines per file
+    #[arg(long)]
+    pub(crate) count: bool,
+    /// Select the lines that do not match
+    #[arg(short = 'v', long)]
+    pub(crate) invert_match: bool,
+    /// Search whole files so matches can span lines ('.' also matches newlines)
+    #[arg(short = 'U', long, conflicts_with = "invert_match")]
+    pub(crate) multiline: bool,
+    /// Skip files larger than SIZE in multiline mode (e.g. 512K, 64M)
+    #[arg(long, value_name = "SIZE", default_value = "64M", value_parser = crate::file_handler::parse_size)]
+    pub(crate) multiline_limit: u64,
+    /// Print one JSON object per line and a summary object at the end
+    #[arg(long, conflicts_with_all = ["files_with_matches", "files_without_match", "count"])]
+    pub(crate) json: bool,
+    /// Search inside .gz/.bz2/.xz/.zst files and zip/tar archives
+    #[arg(short = 'z', long)]
+    pub(crate) search_zip: bool,
+    /// With --search-zip, give up on a file or archive entry that decompresses to more than SIZE
+    #[arg(long, value_name = "SIZE", default_value = "1G", value_parser = crate::file_handler::parse_size)]
+    pub(crate) search_zip_limit: u64,
+    /// What to do with binary files
+    #[arg(long, value_enum, default_value_t = BinaryMode::Skip)]
+    pub(crate) binary: BinaryMode,
+    #[command(flatten)]
+    pub(crate) parallel: ParallelArgs,
     // pub(crate) save_output: bool,
     // pub(crate) save_output_path: String,
 }
 
+impl SearchArgs {
+    /// The positional PATTERN: the last input, unless -e or -f gives the patterns.
+    pub(crate) fn pattern(&self) -> Option<&str> {
+        if !self.regexp.is_empty() || self.patterns_file.is_some() {
+            return None;
+        }
+        self.inputs.last().map(String::as_str)
+    }
+
+    /// The inputs before PATTERN.
+    pub(crate) fn paths(&self) -> &[String] {
+        match self.pattern() {
+            Some(_) => &self.inputs[..self.inputs.len() - 1],
+            None => &self.inputs,
+        }
+    }
+}
+
 #[derive(Parser)]
 pub struct ReplaceArgs {
     pub(crate) path: PathBuf,
     pub(crate) old_string: String,
     pub(crate) new_string: String,
+    /// Treat OLD_STRING as a regex; NEW_STRING may then use $1 or ${name}
+    #[arg(short = 'r', long)]
+    pub(crate) regex: bool,
+    /// Ignore case when matching OLD_STRING
+    #[arg(short = 'i', long)]
+    pub(crate) case_insensitive: bool,
+    /// Replace at most NUM matches per file
+    #[arg(short = 'm', long, value_name = "NUM")]
+    pub(crate) max_replacements: Option<usize>,
+    /// Only replace within these lines, e.g. 10:200, 10: or :200
+    #[arg(long, value_name = "START:END", value_parser = crate::file_handler::parse_line_range)]
+    pub(crate) lines: Option<LineRange>,
+    /// Only show a diff of what would change; nothing is written
     #[arg(short = 'D', long)]
     pub(crate) dry_run: bool,
+    /// Show each change and ask before applying it
+    #[arg(short = 'I', long, conflicts_with = "dry_run")]
+    pub(crate) interactive: bool,
     #[arg(short = 'b', long)]
     pub(crate) backup: bool,
+    /// What to do with binary files
+    #[arg(long, value_enum, default_value_t = BinaryMode::Skip)]
+    pub(crate) binary: BinaryMode,
+    #[command(flatten)]
+    pub(crate) parallel: ParallelArgs,
 }
 
 #[derive(Parser)]
@@ -117,19 +240,111 @@ pub struct CountArgs {
     pub(crate) pattern: String,
     #[arg(short = 'r', long)]
     pub(crate) regex: bool,
+    /// What to do with binary files
+    #[arg(long, value_enum, default_value_t = BinaryMode::Skip)]
+    pub(crate) binary: BinaryMode,
+    #[command(flatten)]
+    pub(crate) parallel: ParallelArgs,
+}
+
+/// How `search`, `count` and `replace` spread work over files.
+#[derive(Parser)]
+pub struct ParallelArgs {
+    /// Number of threads to use (default: one per CPU)
+    #[arg(short = 'j', long, value_name = "NUM")]
+    pub(crate) threads: Option<usize>,
+    /// Print results sorted by path instead of as files finish
+    #[arg(long)]
+    pub(crate) sort: bool,
 }
 
 #[derive(Parser)]
 pub struct RenameArgs {
+    /// File to rename, with --from a directory or glob of files, with --edit a directory
     pub(crate) old_file_name: String,
-    pub(crate) new_file_name: String,
+    #[arg(required_unless_present_any = ["from", "edit"], conflicts_with_all = ["from", "edit"])]
+    pub(crate) new_file_name: Option<String>,
+    /// Regex matched against each file name; the matched part is replaced by --to
+    #[arg(long, value_name = "REGEX", requires = "to")]
+    pub(crate) from: Option<String>,
+    /// Template for the matched part: {1} or {name} for groups, {n} counter, {date}, {ext}, {stem};
+    /// after a colon a width (03), lower, upper, snake, kebab or a date format (%Y%m%d)
+    #[arg(long, value_name = "TEMPLATE", requires = "from")]
+    pub(crate) to: Option<String>,
+    /// Edit the names of the entries in the directory with $EDITOR, one per line
+    #[arg(short = 'e', long, conflicts_with = "from")]
+    pub(crate) edit: bool,
+    /// Show the new names and rename nothing
+    #[arg(short = 'D', long, conflicts_with = "new_file_name")]
+    pub(crate) dry_run: bool,
 }
 
 #[derive(Parser)]
 pub struct RemoveArgs {
+    /// File, directory or glob pattern (e.g. "logs/*.log")
     pub(crate) path: String,
-    // #[arg(short = 'r', long)]
-    pub(crate) option: String,
+    /// Inside directories, remove only files and keep the directory tree
+    #[arg(short = 'f', long, action = ArgAction::SetTrue, default_value_t = false, conflicts_with_all = ["dirs_only", "recursive"])]
+    pub(crate) files_only: bool,
+    /// Inside directories, remove only subdirectories that contain no files
+    #[arg(short = 'd', long, action = ArgAction::SetTrue, default_value_t = false, conflicts_with = "recursive")]
+    pub(crate) dirs_only: bool,
+    /// Remove directories and everything in them
+    #[arg(short = 'r', long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) recursive: bool,
+    /// Ignore missing paths and never ask for confirmation
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) force: bool,
+    /// Only list what would be removed
+    #[arg(short = 'D', long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) dry_run: bool,
+    /// Delete for real instead of moving to the trash
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) permanent: bool,
+}
+
+#[derive(Parser)]
+pub struct TrashArgs {
+    #[command(subcommand)]
+    pub(crate) command: TrashCommands,
+}
+
+#[derive(Parser)]
+pub struct UndoArgs {
+    /// Id of the operation to undo, as listed by `history`
+    #[arg(conflicts_with = "last")]
+    pub(crate) id: Option<String>,
+    /// Undo the N most recent operations not undone yet
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub(crate) last: usize,
+}
+
+#[derive(Parser)]
+pub struct HistoryArgs {
+    /// Forget recorded operations and the contents kept to undo them,
+    /// instead of listing them
+    #[arg(long)]
+    pub(crate) prune: bool,
+    /// With --prune, only forget operations older than this age (e.g. 12h, 7d, 2w)
+    #[arg(long, requires = "prune")]
+    pub(crate) older_than: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum TrashCommands {
+    /// List trashed files
+    #[command(aliases = ["ls"])]
+    List,
+
+    /// Restore a trashed file to its original path
+    Restore { id: String },
+
+    /// Permanently delete trashed files
+    Empty {
+        /// Only delete entries older than this age (e.g. 30m, 12h, 7d, 2w)
+        #[arg(long)]
+        older_than: Option<String>,
+    },
 }
 
 #[derive(Parser)]
@@ -162,22 +377,118 @@ pub struct MoveArgs {
 }
 
 #[derive(Parser)]
+#[command(group(clap::ArgGroup::new("existing").args(["skip_existing", "overwrite", "rename"])))]
 pub struct DecompressArgs {
     pub(crate) path: PathBuf,
+    /// Directory to extract into (created if missing). Archives default to the
+    /// current dir, single compressed files to the input's own dir
+    #[arg(short = 'o', long)]
+    pub(crate) output_dir: Option<PathBuf>,
+    /// Drop absolute and `..` components from entry names instead of refusing the archive
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) strip_unsafe: bool,
+    /// Keep existing files and skip the entry
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) skip_existing: bool,
+    /// Replace existing files without asking
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) overwrite: bool,
+    /// Extract next to existing files as "name (1).ext"
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub(crate) rename: bool,
+    /// Only extract entries matching this glob (repeatable)
+    #[arg(long)]
+    pub(crate) include: Vec<String>,
+    /// Skip entries matching this glob (repeatable)
+    #[arg(long)]
+    pub(crate) exclude: Vec<String>,
+    /// Write the single selected entry to stdout instead of extracting
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false, conflicts_with = "output_dir")]
+    pub(crate) stdout: bool,
+}
+
+#[derive(Parser)]
+pub struct ArchiveArgs {
+    #[command(subcommand)]
+    pub(crate) command: ArchiveCommands,
+}
+
+#[derive(Subcommand)]
+pub enum ArchiveCommands {
+    /// Show entry names, sizes, compression ratios and modification times
+    #[command(aliases = ["ls"])]
+    List {
+        path: PathBuf,
+        /// Print the listing as JSON
+        #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+        json: bool,
+        #[arg(short = 'H', long, action = ArgAction::SetTrue, default_value_t = false)]
+        human_readable: bool,
+    },
+
+    /// Read every entry and verify its checksums
+    Test { path: PathBuf },
+}
+
+#[derive(Parser)]
+pub struct IdentifyArgs {
+    #[arg(required = true)]
+    pub(crate) paths: Vec<PathBuf>,
 }
 
 #[derive(Parser)]
 pub struct CompressArgs {
     #[clap(short, long, value_parser, num_args = 1.., value_delimiter = ' ')]
     pub files: Vec<String>,
+    /// Output file: .zip, .tar, .tar.gz/.tgz, .tar.bz2, .tar.xz, .tar.zst, or
+    /// .gz/.bz2/.xz/.zst for a single file
     pub output_compress: String,
+    /// Compression level (gzip/deflate/xz 0-9, bzip2 1-9, zstd 1-22)
+    #[arg(short = 'l', long)]
+    pub level: Option<i32>,
+    /// Zip only: how entries are compressed [default: deflate]
+    #[arg(short = 'm', long, value_enum)]
+    pub method: Option<ZipMethod>,
+    #[command(flatten)]
+    pub source_filter: SourceFilterArgs,
 }
 
+/// Which files inside the given directories end up in an archive.
 #[derive(Parser)]
-pub struct CompressArgsGz {
-    pub output_compress: String,
-    #[clap(short = 'f', long, value_parser, num_args = 1.., value_delimiter = ' ')]
-    pub files: Vec<String>,
+pub struct SourceFilterArgs {
+    /// Skip files and directories matching this gitignore-style glob (repeatable)
+    #[arg(short = 'e', long)]
+    pub exclude: Vec<String>,
+    /// Skip what .gitignore/.ignore files (and git's excludes) ignore, and `.git` itself
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = false)]
+    pub respect_gitignore: bool,
+}
+
+/// Lines `start` to `end` of a file, both included and counted from 1;
+/// no `end` runs to the last line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: Option<usize>,
+}
+
+/// How `search`, `count` and `replace` treat files that are not text.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum BinaryMode {
+    /// Leave them out and report how many were skipped
+    Skip,
+    /// Read them as text anyway
+    Text,
+    /// Stop with an error
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ZipMethod {
+    Stored,
+    Deflate,
+    Bzip2,
+    Zstd,
 }
 
 #[derive(Parser)]
@@ -0,0 +1,294 @@
+use crate::archive::Codec;
+use crate::command::IdentifyArgs;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// How much of a file is looked at to decide what it is.
+const SAMPLE_SIZE: usize = 8192;
+
+/// What a file looks like from its first bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    Empty,
+    Zip,
+    Tar,
+    /// gzip, bzip2, xz or zstd data; never `Codec::None`.
+    Compressed(Codec),
+    Json,
+    /// Delimiter separated text, with the delimiter found (`,` or `;`).
+    Csv(u8),
+    Tsv,
+    Utf16Text,
+    Text,
+    Binary,
+}
+
+/// The kind of a file and, for compressed files, the kind of what is inside.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Identity {
+    pub kind: FileKind,
+    pub inner: Option<FileKind>,
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileKind::Empty => write!(f, "empty"),
+            FileKind::Zip => write!(f, "zip archive"),
+            FileKind::Tar => write!(f, "tar archive"),
+            FileKind::Compressed(Codec::Gzip) => write!(f, "gzip compressed data"),
+            FileKind::Compressed(Codec::Bzip2) => write!(f, "bzip2 compressed data"),
+            FileKind::Compressed(Codec::Xz) => write!(f, "xz compressed data"),
+            FileKind::Compressed(Codec::Zstd) => write!(f, "zstd compressed data"),
+            FileKind::Compressed(Codec::None) => write!(f, "data"),
+            FileKind::Json => write!(f, "JSON data"),
+            FileKind::Csv(delimiter) => write!(f, "CSV text ('{}' separated)", *delimiter as char),
+            FileKind::Tsv => write!(f, "TSV text"),
+            FileKind::Utf16Text => write!(f, "UTF-16 text"),
+            FileKind::Text => write!(f, "text"),
+            FileKind::Binary => write!(f, "binary data"),
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner {
+            Some(inner) => write!(f, "{}, containing {}", self.kind, inner),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+pub fn run_identify(args: &IdentifyArgs) -> anyhow::Result<()> {
+    let mut failed = false;
+
+    for path in &args.paths {
+        if path.is_dir() {
+            println!("{}: directory", path.display());
+            continue;
+        }
+
+        match identify(path) {
+            Ok(identity) => println!("{}: {}", path.display(), identity),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        return Err(anyhow::anyhow!(
+            "Error: Some files could not be identified."
+        ));
+    }
+    Ok(())
+}
+
+/// Identifies a file from its content, looking through one compression layer.
+pub fn identify(path: &Path) -> anyhow::Result<Identity> {
+    let (head, complete) = read_sample(File::open(path)?)?;
+    let kind = sniff(&head, complete);
+
+    // A broken stream still has a recognisable header; just say nothing
+    // about what is inside.
+    let inner = match kind {
+        FileKind::Compressed(codec) => codec
+            .decoder(File::open(path)?)
+            .and_then(read_sample)
+            .ok()
+            .map(|(head, complete)| sniff(&head, complete)),
+        _ => None,
+    };
+
+    Ok(Identity { kind, inner })
+}
+
+fn read_sample<R: Read>(reader: R) -> io::Result<(Vec<u8>, bool)> {
+    let mut head = Vec::with_capacity(SAMPLE_SIZE);
+    reader.take(SAMPLE_SIZE as u64 + 1).read_to_end(&mut head)?;
+    let complete = head.len() <= SAMPLE_SIZE;
+    head.truncate(SAMPLE_SIZE);
+    Ok((head, complete))
+}
+
+/// Decides what `head` is. `complete` tells whether `head` is the whole
+/// file or only its beginning.
+pub fn sniff(head: &[u8], complete: bool) -> FileKind {
+    if head.is_empty() {
+        return FileKind::Empty;
+    }
+    if is_zip_magic(head) {
+        return FileKind::Zip;
+    }
+
+    let codec = Codec::from_magic(head);
+    if codec != Codec::None {
+        return FileKind::Compressed(codec);
+    }
+    if is_tar_header(head) {
+        return FileKind::Tar;
+    }
+    if is_utf16(head) {
+        return FileKind::Utf16Text;
+    }
+
+    let text = match text_prefix(head, complete) {
+        Some(text) => text,
+        None => return FileKind::Binary,
+    };
+
+    if looks_like_json(text, complete) {
+        FileKind::Json
+    } else if let Some(delimiter) = csv_delimiter(text, complete) {
+        if delimiter == b'\t' {
+            FileKind::Tsv
+        } else {
+            FileKind::Csv(delimiter)
+        }
+    } else {
+        FileKind::Text
+    }
+}
+
+/// Whether `head`, the start of a file, is something other than UTF-8 text:
+/// it has NUL bytes or invalid UTF-8, is UTF-16, or is compressed.
+pub fn is_binary(head: &[u8]) -> bool {
+    Codec::from_magic(head) != Codec::None || is_utf16(head) || text_prefix(head, false).is_none()
+}
+
+/// Local file header, or the end-of-central-directory record of an empty zip.
+pub fn is_zip_magic(head: &[u8]) -> bool {
+    head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06")
+}
+
+/// A tar stream starts with a 512 byte header carrying the `ustar` magic
+/// (POSIX and GNU) or, for old v7 archives, at least a valid checksum.
+pub fn is_tar_header(block: &[u8]) -> bool {
+    if block.len() < 512 {
+        return false;
+    }
+
+    if &block[257..262] == b"ustar" {
+        return true;
+    }
+
+    let stored = std::str::from_utf8(&block[148..156])
+        .ok()
+        .map(|s| s.trim_matches(|c: char| c == '\0' || c == ' '))
+        .and_then(|s| u32::from_str_radix(s, 8).ok());
+
+    let computed: u32 = block[..512]
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                b' ' as u32
+            } else {
+                b as u32
+            }
+        })
+        .sum();
+
+    // An empty name means padding, not an entry, whatever the checksum says.
+    block[0] != 0 && stored == Some(computed)
+}
+
+/// UTF-16 with a byte order mark, or mostly ASCII where every other byte is NUL.
+fn is_utf16(head: &[u8]) -> bool {
+    if head.starts_with(&[0xff, 0xfe]) || head.starts_with(&[0xfe, 0xff]) {
+        return true;
+    }
+
+    let pairs = head.len() / 2;
+    if pairs < 4 {
+        return false;
+    }
+
+    let nul_at = |offset: usize| {
+        head.chunks_exact(2)
+            .filter(|pair| pair[offset] == 0 && pair[1 - offset] != 0)
+            .count()
+    };
+    nul_at(1) * 10 >= pairs * 9 || nul_at(0) * 10 >= pairs * 9
+}
+
+/// Returns `head` as UTF-8 text (without a BOM), or `None` when it is binary:
+/// it contains NUL bytes or is not valid UTF-8. A character cut off at the
+/// end of an incomplete sample does not count against it.
+fn text_prefix(head: &[u8], complete: bool) -> Option<&str> {
+    if head.contains(&0) {
+        return None;
+    }
+
+    let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    match std::str::from_utf8(head) {
+        Ok(text) => Some(text),
+        Err(e) if !complete && e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+fn looks_like_json(text: &str, complete: bool) -> bool {
+    let trimmed = text.trim_start();
+    if !trimmed.starts_with('{') && !trimmed.starts_with('[') {
+        return false;
+    }
+
+    if complete {
+        serde_json::from_str::<serde_json::Value>(text).is_ok()
+    } else {
+        // Only the start is known; accept it unless the parser already
+        // choked on something other than running out of input.
+        match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(_) => true,
+            Err(e) => e.is_eof(),
+        }
+    }
+}
+
+/// Finds a delimiter (tab, comma or semicolon) that splits every line of
+/// `text` into the same number of fields, at least two lines being needed.
+fn csv_delimiter(text: &str, complete: bool) -> Option<u8> {
+    let mut lines: Vec<&str> = text.lines().collect();
+    if !complete {
+        // The last line is probably cut short.
+        lines.pop();
+    }
+    let lines: Vec<&str> = lines
+        .into_iter()
+        .filter(|line| !line.trim().is_empty())
+        .take(20)
+        .collect();
+    if lines.len() < 2 {
+        return None;
+    }
+
+    [b'\t', b',', b';'].into_iter().find(|&delimiter| {
+        let first = count_fields(lines[0], delimiter);
+        first > 1
+            && lines
+                .iter()
+                .all(|line| count_fields(line, delimiter) == first)
+    })
+}
+
+/// Counts fields on one line, ignoring delimiters inside double quotes.
+fn count_fields(line: &str, delimiter: u8) -> usize {
+    let mut fields = 1;
+    let mut quoted = false;
+
+    for b in line.bytes() {
+        if b == b'"' {
+            quoted = !quoted;
+        } else if b == delimiter && !quoted {
+            fields += 1;
+        }
+    }
+    fields
+}
@@ -1,22 +1,38 @@
-use crate::command::{CatFileArgs, CompressArgs, CompressArgsGz, CopyArgs, CountArgs, CsvToJsonArgs, DecompressArgs, JsonToCsvArgs, ListArgs, ReadTableCsvArgs, RemoveArgs, RenameArgs, ReplaceArgs, SearchArgs};
+use crate::archive::{detect_format, format_from_name, open_decoded, Codec, Format};
+use crate::command::{
+    BinaryMode, CatFileArgs, CompressArgs, CopyArgs, CountArgs, CsvToJsonArgs, DecompressArgs,
+    JsonToCsvArgs, LineRange, ListArgs, MoveArgs, ParallelArgs, ReadTableCsvArgs, RemoveArgs,
+    RenameArgs, ReplaceArgs, SearchArgs, SourceFilterArgs, ZipMethod,
+};
+use crate::detect::{identify, is_binary, FileKind};
+use crate::journal::{Journal, Operation};
+use crate::matcher::{Matcher, Span};
+use crate::trash::Trash;
 use anyhow::{anyhow, Context};
 use csv::{ReaderBuilder, Writer};
-use flate2::read::{GzDecoder, GzEncoder};
-use flate2::Compression;
-use ignore::WalkBuilder;
-use regex::{Regex, RegexBuilder};
+use ignore::overrides::OverrideBuilder;
+use ignore::types::TypesBuilder;
+use ignore::{WalkBuilder, WalkState};
+use regex::Regex;
 use serde_json::{json, Value};
+use std::collections::VecDeque;
 use std::fs::{DirEntry, File, Metadata};
-use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::io::{BufRead, BufReader, ErrorKind, IsTerminal, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{fs, io};
 use tar::Builder;
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use termcolor::{BufferWriter, Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use walkdir::WalkDir;
 use zip::write::FileOptions;
 use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
+/// Buffer size for reading files that are searched or counted line by line.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
 pub fn run_tree(args: &ListArgs) -> anyhow::Result<()> {
     let mut stdout = StandardStream::stdout(if args.color {
         ColorChoice::Always
@@ -64,570 +80,2729 @@ pub fn run_tree(args: &ListArgs) -> anyhow::Result<()> {
 }
 
 pub fn run_search(args: &SearchArgs) -> anyhow::Result<()> {
-    let (search_dir, file_name_pattern) = extract_path_and_pattern(&args.path)?;
-
-    println!("Search dir: {}", search_dir.display());
-    println!("File pattern: {}", file_name_pattern);
-
-    let file_regex = Regex::new(&file_name_pattern)
-        .with_context(|| format!("Invalid regex pattern for filename: {}", file_name_pattern))?;
-
-    let regex_search = RegexBuilder::new(&args.pattern)
-        .case_insensitive(args.case_insensitive)
-        .build()
-        .with_context(|| format!("Invalid regex pattern: {}", args.pattern))?;
+    let matcher = Matcher::from_args(args)?;
 
-    let mut stdout = if args.color {
-        StandardStream::stdout(ColorChoice::Always)
+    let color_choice = if args.color {
+        ColorChoice::Always
     } else {
-        StandardStream::stdout(ColorChoice::Never)
+        ColorChoice::Never
     };
+    let stdout = BufferWriter::stdout(color_choice);
+
+    let mut builder = search_walker(args)?;
+
+    let started = Instant::now();
+    let (before, after) = search_context(args);
+    let mut printed_group = false;
+    let mut stats = SearchStats::default();
+    let skipped = AtomicUsize::new(0);
+
+    walk_files_parallel(
+        &mut builder,
+        &args.parallel,
+        |path| {
+            let mut buffer = stdout.buffer();
+            let file_stats = search_path(path, &matcher, args, &mut buffer, &skipped)?;
+            Ok(Some((buffer, file_stats)))
+        },
+        |_, (buffer, file_stats)| {
+            stats.add(&file_stats);
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            // Context groups of different files are separated like groups
+            // within one file.
+            if printed_group && !args.json && (before > 0 || after > 0) {
+                let mut separator = stdout.buffer();
+                writeln!(separator, "--")?;
+                stdout.print(&separator)?;
+            }
+            stdout.print(&buffer)?;
+            printed_group = true;
+            Ok(())
+        },
+    )?;
+
+    if args.json {
+        let summary = json!({
+            "type": "summary",
+            "files_scanned": stats.files,
+            "bytes_scanned": stats.bytes,
+            "matches": stats.matches,
+            "elapsed_secs": started.elapsed().as_secs_f64(),
+        });
+        println!("{}", summary);
+    }
 
-    let mut stdout_file_name = StandardStream::stdout(ColorChoice::Always);
+    report_skipped_binary(&skipped);
+    Ok(())
+}
 
-    let mut binding = ColorSpec::new();
+/// Builds the walk over the PATH arguments of `search`, filtered by
+/// `--glob`, `--iglob`, `--type` and `--respect-gitignore`. Files named
+/// directly are always searched.
+pub(crate) fn search_walker(args: &SearchArgs) -> anyhow::Result<WalkBuilder> {
+    // With --search-zip, `*.log` also picks up `app.log.gz`.
+    let suffixes: Vec<&str> = if args.search_zip {
+        [Codec::Gzip, Codec::Bzip2, Codec::Xz, Codec::Zstd]
+            .iter()
+            .flat_map(|codec| codec.suffixes())
+            .copied()
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let paths = expand_search_paths(args.paths(), &suffixes)?;
+
+    let mut overrides = OverrideBuilder::new(".");
+    for (globs, case_insensitive) in [(&args.glob, false), (&args.iglob, true)] {
+        overrides.case_insensitive(case_insensitive)?;
+        for glob in globs {
+            overrides
+                .add(glob)
+                .with_context(|| format!("Invalid glob: {}", glob))?;
+            if !glob.starts_with('!') {
+                for suffix in &suffixes {
+                    overrides.add(&format!("{}{}", glob, suffix))?;
+                }
+            }
+        }
+    }
 
-    let color_spec = binding.set_fg(Some(Color::Red)).set_bold(true);
+    let mut types = TypesBuilder::new();
+    types.add_defaults();
+    for name in &args.file_type {
+        let globs = types
+            .definitions()
+            .into_iter()
+            .find(|def| def.name() == name)
+            .map(|def| def.globs().to_vec())
+            .ok_or_else(|| anyhow!("Error: Unknown file type '{}'.", name))?;
+        for glob in globs {
+            for suffix in &suffixes {
+                types.add(name, &format!("{}{}", glob, suffix))?;
+            }
+        }
+        types.select(name);
+    }
 
-    let walker = WalkBuilder::new(&search_dir)
+    let mut builder = WalkBuilder::new(&paths[0]);
+    for path in &paths[1..] {
+        builder.add(path);
+    }
+    builder
+        .standard_filters(false)
         .hidden(args.hidden)
-        .ignore(false)
-        .build();
+        .git_ignore(args.respect_gitignore)
+        .git_exclude(args.respect_gitignore)
+        .git_global(args.respect_gitignore)
+        .ignore(args.respect_gitignore)
+        .parents(args.respect_gitignore)
+        .require_git(false)
+        .overrides(overrides.build()?)
+        .types(types.build()?);
+    Ok(builder)
+}
 
-    for result in walker {
-        let entry = result?;
-        let path = entry.path();
+/// Existing paths are taken as they are, anything else as a glob such as
+/// `logs/*.log`, also tried with each of `suffixes`. No paths at all means
+/// the current directory.
+pub(crate) fn expand_search_paths(
+    inputs: &[String],
+    suffixes: &[&str],
+) -> anyhow::Result<Vec<PathBuf>> {
+    if inputs.is_empty() {
+        return Ok(vec![PathBuf::from(".")]);
+    }
 
-        if path.is_dir() || path.is_symlink() {
+    let mut paths = Vec::new();
+    for input in inputs {
+        let exists = fs::symlink_metadata(input).is_ok();
+        if exists || !input.contains(['*', '?', '[']) {
+            if !exists {
+                return Err(anyhow!("Error: Path '{}' does not exist.", input));
+            }
+            paths.push(PathBuf::from(input));
             continue;
         }
 
-        if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-            if !file_regex.is_match(file_name) {
-                // println!("{} is not a file", path.display());
-                continue;
+        let mut patterns = vec![input.clone()];
+        patterns.extend(suffixes.iter().map(|suffix| format!("{}{}", input, suffix)));
+
+        let mut matched = Vec::new();
+        for pattern in &patterns {
+            for entry in
+                glob::glob(pattern).with_context(|| format!("Invalid glob pattern: {}", input))?
+            {
+                matched.push(entry?);
             }
         }
+        if matched.is_empty() {
+            return Err(anyhow!("Error: Nothing matches '{}'.", input));
+        }
+        matched.sort();
+        paths.extend(matched);
+    }
+    Ok(paths)
+}
 
-        let file =
-            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
-
-        let reader = BufReader::new(file);
+/// What a search went through, summed over files for `--json`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct SearchStats {
+    pub files: u64,
+    pub bytes: u64,
+    pub matches: u64,
+}
 
-        for (line_num, line) in reader.lines().enumerate() {
-            let line = match line {
-                Ok(line) => line,
-                Err(err) => match err.kind() {
-                    _ => {
-                        eprintln!("Error this: {} at line {}", err, line_num + 1);
-                        continue;
-                    }
-                },
-            };
+impl SearchStats {
+    fn add(&mut self, other: &SearchStats) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.matches += other.matches;
+    }
+}
 
-            let mut last_match = 0;
+/// Searches the file `path`. With `--search-zip`, compressed files are
+/// decompressed first and every file inside a zip or tar archive is searched,
+/// reported as `archive.zip!inner/path`. A file that cannot be read or
+/// decoded is reported on stderr and does not stop the search, unless it is
+/// a binary file under `--binary error`.
+pub(crate) fn search_path<W: WriteColor>(
+    path: &Path,
+    matcher: &Matcher,
+    args: &SearchArgs,
+    out: &mut W,
+    skipped: &AtomicUsize,
+) -> anyhow::Result<SearchStats> {
+    match search_file(path, matcher, args, out, skipped) {
+        Err(e) if !e.is::<BinaryFileError>() => {
+            eprintln!("{:#}", e);
+            Ok(SearchStats::default())
+        }
+        result => result,
+    }
+}
 
-            if regex_search.is_match(&line) {
-                stdout_file_name.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)))?;
-                write!(
-                    &mut stdout_file_name,
-                    "{}:{}: ",
-                    path.display(),
-                    line_num + 1
-                )?;
+fn search_file<W: WriteColor>(
+    path: &Path,
+    matcher: &Matcher,
+    args: &SearchArgs,
+    out: &mut W,
+    skipped: &AtomicUsize,
+) -> anyhow::Result<SearchStats> {
+    let label = path.display().to_string();
+    if !args.search_zip {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        return search_entry(&label, file, matcher, args, out, skipped);
+    }
 
-                stdout_file_name.reset()?;
+    let mut stats = SearchStats::default();
+    match detect_format(path)? {
+        Format::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)
+                .with_context(|| format!("Failed to read {}", label))?;
+            for i in 0..archive.len() {
+                let entry = archive.by_index(i)?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let inner = format!("{}!{}", label, entry.name());
+                let entry = CappedReader::new(entry, args.search_zip_limit);
+                stats.add(&search_entry(&inner, entry, matcher, args, out, skipped)?);
+            }
+        }
+        Format::Tar(codec) => {
+            let mut archive = tar::Archive::new(open_decoded(path, codec)?);
+            for entry in archive
+                .entries()
+                .with_context(|| format!("Failed to read {}", label))?
+            {
+                let entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let inner = format!("{}!{}", label, entry.path()?.display());
+                let entry = CappedReader::new(entry, args.search_zip_limit);
+                stats.add(&search_entry(&inner, entry, matcher, args, out, skipped)?);
             }
+        }
+        Format::Stream(codec) => {
+            let reader = CappedReader::new(open_decoded(path, codec)?, args.search_zip_limit);
+            stats = search_entry(&label, reader, matcher, args, out, skipped)?;
+        }
+    }
+    Ok(stats)
+}
 
-            for mat in regex_search.find_iter(&line) {
-                write!(&mut stdout, "{}", &line[last_match..mat.start()])?;
+/// Fails once more than `limit` bytes came out of `inner`, so a small
+/// compressed file cannot expand without bound.
+struct CappedReader<R> {
+    inner: R,
+    left: u64,
+    limit: u64,
+}
 
-                if args.color {
-                    stdout.set_color(&color_spec)?;
-                }
-                write!(&mut stdout, "{}", &line[mat.start()..mat.end()])?;
-                if args.color {
-                    stdout.reset()?;
-                }
+impl<R: Read> CappedReader<R> {
+    fn new(inner: R, limit: u64) -> Self {
+        CappedReader {
+            inner,
+            left: limit,
+            limit,
+        }
+    }
+}
 
-                last_match = mat.end();
-            }
+impl<R: Read> Read for CappedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.left {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "decompresses to more than {} (see --search-zip-limit)",
+                    format_size(self.limit, true)
+                ),
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
 
-            if regex_search.is_match(&line) {
-                writeln!(&mut stdout, "{}", &line[last_match..])?;
-            }
+/// Searches one file, or one file inside an archive, unless `--binary` says
+/// to leave it out.
+fn search_entry<R: Read, W: WriteColor>(
+    label: &str,
+    reader: R,
+    matcher: &Matcher,
+    args: &SearchArgs,
+    out: &mut W,
+    skipped: &AtomicUsize,
+) -> anyhow::Result<SearchStats> {
+    let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, reader);
+    let head = reader
+        .fill_buf()
+        .with_context(|| format!("Failed to read {}", label))?;
+    if !read_as_text(Path::new(label), head, args.binary, skipped)? {
+        return Ok(SearchStats::default());
+    }
+
+    if args.multiline {
+        let mut contents = Vec::new();
+        reader
+            .take(args.multiline_limit + 1)
+            .read_to_end(&mut contents)
+            .with_context(|| format!("Failed to read {}", label))?;
+        if contents.len() as u64 > args.multiline_limit {
+            eprintln!(
+                "Skipped {}: larger than the multiline limit of {}",
+                label,
+                format_size(args.multiline_limit, true)
+            );
+            return Ok(SearchStats::default());
         }
+        return search_multiline(
+            label,
+            &String::from_utf8_lossy(&contents),
+            matcher,
+            args,
+            out,
+        );
     }
 
-    Ok(())
+    search_reader(label, reader, matcher, args, out)
+        .with_context(|| format!("Failed to read {}", label))
 }
 
-fn extract_path_and_pattern(input: &str) -> anyhow::Result<(PathBuf, String)> {
-    if let Some((dir, pattern)) = input.rsplit_once('/') {
-        let dir_path = PathBuf::from(dir);
-        let file_name_pattern = wildcard_to_regex(pattern)?;
-        Ok((dir_path, file_name_pattern))
-    } else {
-        anyhow::bail!("Invalid path pattern. Example: /var/logs/trc*");
+/// A binary file met under `--binary error`, which stops the whole run.
+#[derive(Debug)]
+pub(crate) struct BinaryFileError(PathBuf);
+
+impl std::fmt::Display for BinaryFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Error: {} is a binary file. Use --binary=text to read it anyway.",
+            self.0.display()
+        )
     }
 }
 
-fn wildcard_to_regex(pattern: &str) -> anyhow::Result<String> {
-    let regex_str = regex::escape(pattern)
-        .replace("\\*", ".*")
-        .replace("\\?", ".")
-        .replace(r"\-", "-"); // Keep `-` unescaped so it behaves naturally
+impl std::error::Error for BinaryFileError {}
+
+/// Applies `--binary` to the file `path` starting with `head`: returns
+/// whether to read it as text, counting it in `skipped` when it is not.
+fn read_as_text(
+    path: &Path,
+    head: &[u8],
+    mode: BinaryMode,
+    skipped: &AtomicUsize,
+) -> anyhow::Result<bool> {
+    if mode == BinaryMode::Text || !is_binary(head) {
+        return Ok(true);
+    }
 
-    Ok(format!("^{}$", regex_str))
+    if mode == BinaryMode::Error {
+        return Err(BinaryFileError(path.to_path_buf()).into());
+    }
+    skipped.fetch_add(1, Ordering::Relaxed);
+    Ok(false)
 }
-pub fn run_replace(args: &ReplaceArgs) -> anyhow::Result<()> {
-    let mut builder = WalkBuilder::new(&args.path);
-    builder.hidden(true).ignore(false);
 
-    for result in builder.build() {
-        let entry = result?;
-        let path = entry.path();
+fn report_skipped_binary(skipped: &AtomicUsize) {
+    let skipped = skipped.load(Ordering::Relaxed);
+    if skipped > 0 {
+        eprintln!(
+            "Skipped {} binary file(s); use --binary=text to include them.",
+            skipped
+        );
+    }
+}
 
-        if path.is_dir() || path.is_symlink() {
-            continue;
-        }
+/// Lines of context asked for before and after each selected line. Modes
+/// printing only names or counts show no context.
+fn search_context(args: &SearchArgs) -> (usize, usize) {
+    if args.files_with_matches || args.files_without_match || args.count {
+        return (0, 0);
+    }
+    (
+        args.before_context.or(args.context).unwrap_or(0),
+        args.after_context.or(args.context).unwrap_or(0),
+    )
+}
+
+/// Searches one file line by line and writes what `args` asks for: the
+/// selected lines with their context (as text or JSON), a count, or only
+/// the file name.
+pub(crate) fn search_reader<R: BufRead, W: WriteColor>(
+    label: &str,
+    reader: R,
+    matcher: &Matcher,
+    args: &SearchArgs,
+    out: &mut W,
+) -> anyhow::Result<SearchStats> {
+    let (before, after) = search_context(args);
+    let names_or_count = args.files_with_matches || args.files_without_match || args.count;
+
+    let mut before_lines: VecDeque<(usize, String)> = VecDeque::with_capacity(before);
+    let mut after_left = 0;
+    let mut last_printed: Option<usize> = None;
+    let mut matches = 0;
+
+    let print_line = |out: &mut W, line_num: usize, line: &str, selected: bool| {
+        let highlight = selected && !args.invert_match;
+        let hit = SearchHit {
+            first_line: line_num,
+            last_line: line_num,
+            text: line,
+            spans: if highlight {
+                matcher.find_iter(line)
+            } else {
+                Vec::new()
+            },
+            patterns: if highlight && (args.json || matcher.pattern_count() > 1) {
+                matcher.matching_patterns(line)
+            } else {
+                Vec::new()
+            },
+        };
+        write_hit(out, label, &hit, selected, matcher, args)
+    };
 
-        let contents = fs::read_to_string(path)
-            .with_context(|| format!("Failed to read {}", path.display()))?;
+    let bytes = for_each_line(reader, |line_num, line| {
+        let limit_reached = args.max_count.is_some_and(|max| matches >= max);
+        let selected = !limit_reached && matcher.is_match(line) != args.invert_match;
 
-        if contents.contains(&args.old_string) {
-            if args.backup {
-                let backup_path = path.with_extension("bak");
-                fs::copy(path, &backup_path)
-                    .with_context(|| format!("Failed to create backup for {}", path.display()))?;
+        if selected {
+            matches += 1;
+            if args.files_with_matches {
+                return Ok(false);
+            }
+            if names_or_count {
+                return Ok(true);
             }
 
-            let new_contents = contents.replace(&args.old_string, &args.new_string);
-
-            if args.dry_run {
-                println!("Would replace '{}' in {}", args.old_string, path.display());
-            } else {
-                let mut file = File::create(path)
-                    .with_context(|| format!("Failed to open {} for writing", path.display()))?;
-                file.write_all(new_contents.as_bytes())?;
+            let first = before_lines.front().map_or(line_num, |(n, _)| *n);
+            let adjacent = last_printed.is_some_and(|last| first <= last + 1);
+            if last_printed.is_some() && !args.json && (before > 0 || after > 0) && !adjacent {
+                writeln!(out, "--")?;
+            }
 
-                println!("Replaced '{}' in {}", args.old_string, path.display());
+            for (n, text) in before_lines.drain(..) {
+                print_line(out, n, &text, false)?;
+            }
+            print_line(out, line_num, line, true)?;
+
+            last_printed = Some(line_num);
+            after_left = after;
+        } else if after_left > 0 {
+            print_line(out, line_num, line, false)?;
+            last_printed = Some(line_num);
+            after_left -= 1;
+        } else if limit_reached {
+            return Ok(false);
+        } else if before > 0 {
+            if before_lines.len() == before {
+                before_lines.pop_front();
             }
+            before_lines.push_back((line_num, line.to_string()));
         }
-    }
+        Ok(true)
+    })?;
 
-    Ok(())
+    write_file_result(out, label, args, matches)?;
+    Ok(SearchStats {
+        files: 1,
+        bytes,
+        matches,
+    })
 }
 
-pub fn run_count(args: &CountArgs) -> anyhow::Result<()> {
-    let regex = if args.regex {
-        Regex::new(&args.pattern)?
-    } else {
-        Regex::new(&regex::escape(&args.pattern))?
+/// `--multiline`: searches `text`, a whole file, so a match may span lines.
+/// All lines a match touches are selected together, and matches sharing a
+/// line are shown as one.
+fn search_multiline<W: WriteColor>(
+    label: &str,
+    text: &str,
+    matcher: &Matcher,
+    args: &SearchArgs,
+    out: &mut W,
+) -> anyhow::Result<SearchStats> {
+    let (before, after) = search_context(args);
+
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .filter(|&start| start < text.len())
+        .collect();
+    let line_count = line_starts.len();
+    let line_of = |offset: usize| line_starts.partition_point(|&start| start <= offset).max(1);
+    let line_bounds = |line_num: usize| {
+        let start = line_starts[line_num - 1];
+        let mut end = line_starts.get(line_num).copied().unwrap_or(text.len());
+        if text[start..end].ends_with('\n') {
+            end -= 1;
+        }
+        if text[start..end].ends_with('\r') {
+            end -= 1;
+        }
+        (start, end)
     };
 
-    let mut builder = WalkBuilder::new(&args.path);
-    builder.hidden(true).ignore(false);
+    // (first line, last line, matches) of every selected block.
+    let mut blocks: Vec<(usize, usize, Vec<Span>)> = Vec::new();
+    let spans = if line_count > 0 {
+        matcher.find_iter(text)
+    } else {
+        Vec::new()
+    };
+    for span in spans {
+        let first = line_of(span.start);
+        let last = line_of(span.end.saturating_sub(1).max(span.start));
+        let limit_reached = args.max_count.is_some_and(|max| blocks.len() as u64 >= max);
+        match blocks.last_mut() {
+            Some(block) if first <= block.1 => {
+                block.1 = block.1.max(last);
+                block.2.push(span);
+            }
+            _ if limit_reached => break,
+            _ => blocks.push((first, last, vec![span])),
+        }
+    }
 
-    let mut total = 0;
-    let mut file_counts = Vec::new();
+    let names_or_count = args.files_with_matches || args.files_without_match || args.count;
+    let print_context = |out: &mut W, line_num: usize| {
+        let (start, end) = line_bounds(line_num);
+        let hit = SearchHit {
+            first_line: line_num,
+            last_line: line_num,
+            text: &text[start..end],
+            spans: Vec::new(),
+            patterns: Vec::new(),
+        };
+        write_hit(out, label, &hit, false, matcher, args)
+    };
 
-    for result in builder.build() {
-        let entry = result?;
-        let path = entry.path();
+    let mut last_printed: Option<usize> = None;
+    for (i, (first, last, spans)) in blocks.iter().enumerate() {
+        if names_or_count {
+            break;
+        }
 
-        if path.is_dir() || path.is_symlink() {
-            continue;
+        let from = first.saturating_sub(before).max(1);
+        let from = last_printed.map_or(from, |printed| from.max(printed + 1));
+        if last_printed.is_some_and(|printed| from > printed + 1)
+            && !args.json
+            && (before > 0 || after > 0)
+        {
+            writeln!(out, "--")?;
+        }
+        for line_num in from..*first {
+            print_context(out, line_num)?;
         }
 
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let mut count = 0;
+        let start = line_bounds(*first).0;
+        let end = line_bounds(*last).1;
+        let mut patterns: Vec<usize> = spans.iter().map(|span| span.pattern).collect();
+        patterns.sort_unstable();
+        patterns.dedup();
+        let hit = SearchHit {
+            first_line: *first,
+            last_line: *last,
+            text: &text[start..end],
+            spans: spans
+                .iter()
+                .map(|span| Span {
+                    start: span.start.clamp(start, end) - start,
+                    end: span.end.clamp(start, end) - start,
+                    pattern: span.pattern,
+                })
+                .collect(),
+            patterns,
+        };
+        write_hit(out, label, &hit, true, matcher, args)?;
 
-        for line in reader.lines() {
-            let line = line?;
-            if regex.is_match(&line) {
-                count += 1;
-            }
+        // Context after stops where the next block begins.
+        let next_first = blocks.get(i + 1).map_or(usize::MAX, |block| block.0);
+        let to = (last + after).min(line_count).min(next_first - 1);
+        for line_num in last + 1..=to {
+            print_context(out, line_num)?;
         }
+        last_printed = Some(to.max(*last));
+    }
 
-        if count > 0 {
-            file_counts.push((path.display().to_string(), count));
-            total += count;
-        }
+    let matches = blocks.len() as u64;
+    write_file_result(out, label, args, matches)?;
+    Ok(SearchStats {
+        files: 1,
+        bytes: text.len() as u64,
+        matches,
+    })
+}
+
+/// The per-file line of `--count`, `--files-with-matches` and
+/// `--files-without-match`.
+fn write_file_result<W: Write>(
+    out: &mut W,
+    label: &str,
+    args: &SearchArgs,
+    matches: u64,
+) -> anyhow::Result<()> {
+    if args.count && matches > 0 {
+        writeln!(out, "{}:{}", label, matches)?;
+    } else if args.files_with_matches && matches > 0 || args.files_without_match && matches == 0 {
+        writeln!(out, "{}", label)?;
     }
+    Ok(())
+}
+
+/// Text to print for a selected or context line or, with `--multiline`, for
+/// the lines a match spans. `spans` are relative to `text`; `patterns` are
+/// the indices of the patterns found in it.
+struct SearchHit<'a> {
+    first_line: usize,
+    last_line: usize,
+    text: &'a str,
+    spans: Vec<Span>,
+    patterns: Vec<usize>,
+}
 
-    for (file, count) in &file_counts {
-        println!("{}: {}", file, count);
+/// Writes `hit` as JSON or as `path:LINE: text` lines.
+fn write_hit<W: WriteColor>(
+    out: &mut W,
+    label: &str,
+    hit: &SearchHit,
+    selected: bool,
+    matcher: &Matcher,
+    args: &SearchArgs,
+) -> anyhow::Result<()> {
+    if args.json {
+        return write_json_hit(out, label, hit, selected);
     }
 
-    println!("Total matches across all files: {}", total);
+    let separator = if selected { ':' } else { '-' };
+    let show_patterns = selected && !args.invert_match && matcher.pattern_count() > 1;
+
+    let mut offset = 0;
+    for (i, raw_line) in hit.text.split('\n').enumerate() {
+        let line = raw_line.strip_suffix('\r').unwrap_or(raw_line);
+        let spans: Vec<Span> = hit
+            .spans
+            .iter()
+            .filter_map(|span| {
+                let start = span.start.max(offset);
+                let end = span.end.min(offset + line.len());
+                (start < end).then(|| Span {
+                    start: start - offset,
+                    end: end - offset,
+                    pattern: span.pattern,
+                })
+            })
+            .collect();
+        let patterns = (show_patterns && i == 0).then_some(hit.patterns.as_slice());
+        write_search_line(
+            out,
+            label,
+            hit.first_line + i,
+            separator,
+            line,
+            &spans,
+            patterns,
+        )?;
+        offset += raw_line.len() + 1;
+    }
     Ok(())
 }
 
-pub fn rename(args: &RenameArgs) -> anyhow::Result<()> {
-    let path = Path::new(&args.old_file_name);
+/// Calls `f` with the 1-based number and the text of every line, without
+/// its line ending, until `f` returns `false`. Lines are read into one
+/// reused buffer; invalid UTF-8 is replaced rather than skipped. Returns
+/// the number of bytes read.
+fn for_each_line<R: BufRead>(
+    mut reader: R,
+    mut f: impl FnMut(usize, &str) -> anyhow::Result<bool>,
+) -> anyhow::Result<u64> {
+    let mut buf = Vec::new();
+    let mut line_num = 0;
+    let mut bytes = 0;
 
-    match fs::rename(path, &args.new_file_name) {
-        Ok(_) => {
-            println!(
-                "Successfully renamed '{}' to '{}'",
-                &args.old_file_name, &args.new_file_name
-            );
+    loop {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            return Ok(bytes);
         }
-        Err(e) => match e.kind() {
-            ErrorKind::NotFound => {
-                eprintln!("Error: Source file '{}' not found.", &args.old_file_name)
-            }
+        line_num += 1;
+        bytes += read as u64;
 
-            ErrorKind::AlreadyExists => {
-                eprintln!(
-                    "Error: Destination '{}' already exists.",
-                    &args.new_file_name
-                );
-            }
-            ErrorKind::PermissionDenied => {
-                eprintln!(
-                    "Error: Permission denied when renaming '{}'.",
-                    &args.old_file_name
-                );
-            }
-            _ => eprintln!(
-                "Error:
-                Failed to rename '{}': {}",
-                &args.old_file_name, &args.new_file_name
-            ),
-        },
-    }
+        let mut line = buf.as_slice();
+        if let Some(rest) = line.strip_suffix(b"\n") {
+            line = rest.strip_suffix(b"\r").unwrap_or(rest);
+        }
 
-    Ok(())
+        if !f(line_num, &String::from_utf8_lossy(line))? {
+            return Ok(bytes);
+        }
+    }
 }
 
-pub fn list_current_dir() -> anyhow::Result<()> {
-    // Read the entries of the current directory
-    let entries = fs::read_dir(".")?;
+/// Walks `builder` on `parallel.threads` threads and calls `visit` for every
+/// regular file. `emit` gets each file's result on the calling thread, as
+/// files finish or sorted by path with `--sort`, so a file's output is never
+/// interleaved with another's. The first error stops the walk.
+pub(crate) fn walk_files_parallel<T: Send>(
+    builder: &mut WalkBuilder,
+    parallel: &ParallelArgs,
+    visit: impl Fn(&Path) -> anyhow::Result<Option<T>> + Sync,
+    mut emit: impl FnMut(&Path, T) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let walker = builder
+        .threads(parallel.threads.unwrap_or(0))
+        .build_parallel();
+    let quit = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel::<anyhow::Result<(PathBuf, T)>>();
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            walker.run(|| {
+                let tx = tx.clone();
+                let visit = &visit;
+                let quit = &quit;
+                Box::new(move |result| {
+                    if quit.load(Ordering::Relaxed) {
+                        return WalkState::Quit;
+                    }
 
-    // Print a header
-    println!(
-        "{:<10} {:<20} {:<8} {}",
-        "Mode", "LastWriteTime", "Length", "Name"
-    );
-    println!("{:-<10} {:-<20} {:-<8} {:-<1}", "", "", "", "");
+                    let entry = match result {
+                        Ok(entry) => entry,
+                        Err(e) => {
+                            let _ = tx.send(Err(e.into()));
+                            return WalkState::Quit;
+                        }
+                    };
+                    // Links met while walking are skipped, so no file is
+                    // visited twice; a link named as a PATH is followed.
+                    let path = entry.path();
+                    if path.is_dir() || entry.depth() > 0 && entry.path_is_symlink() {
+                        return WalkState::Continue;
+                    }
 
-    for entry_result in entries {
-        let entry = entry_result?;
-        let md = entry.metadata()?;
-        print_entry(&entry, &md)?;
-    }
+                    match visit(path) {
+                        Ok(Some(value)) => {
+                            let _ = tx.send(Ok((path.to_path_buf(), value)));
+                            WalkState::Continue
+                        }
+                        Ok(None) => WalkState::Continue,
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                            WalkState::Quit
+                        }
+                    }
+                })
+            });
+            drop(tx);
+        });
+
+        let mut sorted = Vec::new();
+        for result in rx {
+            let emitted = result.and_then(|(path, value)| {
+                if parallel.sort {
+                    sorted.push((path, value));
+                    Ok(())
+                } else {
+                    emit(&path, value)
+                }
+            });
+            if let Err(e) = emitted {
+                quit.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        for (path, value) in sorted {
+            emit(&path, value)?;
+        }
+        Ok(())
+    })
+}
 
+/// Writes a selected hit as a `match` object, with the byte offsets of its
+/// matches, or a context line as a `context` object. Offsets index `text`,
+/// the line as UTF-8 with invalid bytes (read with `--binary text`) turned
+/// into U+FFFD, so they are file offsets only for valid UTF-8.
+fn write_json_hit<W: Write>(
+    out: &mut W,
+    label: &str,
+    hit: &SearchHit,
+    selected: bool,
+) -> anyhow::Result<()> {
+    let submatches: Vec<Value> = hit
+        .spans
+        .iter()
+        .map(|span| {
+            json!({
+                "start": span.start,
+                "end": span.end,
+                "text": &hit.text[span.start..span.end],
+                "pattern": span.pattern,
+            })
+        })
+        .collect();
+
+    let object = json!({
+        "type": if selected { "match" } else { "context" },
+        "path": label,
+        "line": hit.first_line,
+        "end_line": hit.last_line,
+        "text": hit.text,
+        "submatches": submatches,
+        "patterns": hit.patterns,
+    });
+    writeln!(out, "{}", object)?;
     Ok(())
 }
 
-fn print_entry(entry: &DirEntry, md: &Metadata) -> io::Result<()> {
-    let file_type = md.file_type();
-    let mode = if file_type.is_dir() {
-        "d-----"
-    } else {
-        "-a----"
-    };
+/// Writes `path:LINE: text` for a selected line or `path-LINE- text` for
+/// context, highlighting `spans`. Pattern indices, when given, come first,
+/// e.g. `path:LINE: [0,2] text`.
+fn write_search_line<W: WriteColor>(
+    out: &mut W,
+    label: &str,
+    line_num: usize,
+    separator: char,
+    line: &str,
+    spans: &[Span],
+    patterns: Option<&[usize]>,
+) -> anyhow::Result<()> {
+    out.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)))?;
+    write!(out, "{}{}{}{} ", label, separator, line_num, separator)?;
+    out.reset()?;
+
+    if let Some(patterns) = patterns {
+        let indices: Vec<String> = patterns.iter().map(|i| i.to_string()).collect();
+        out.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+        write!(out, "[{}] ", indices.join(","))?;
+        out.reset()?;
+    }
 
-    let datetime_str = match md.modified() {
-        Ok(time) => format_system_time(time),
-        Err(_) => String::from("Unknown"),
+    let mut last_match = 0;
+    for span in spans {
+        write!(out, "{}", &line[last_match..span.start])?;
+        out.set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))?;
+        write!(out, "{}", &line[span.start..span.end])?;
+        out.reset()?;
+        last_match = span.end;
+    }
+    writeln!(out, "{}", &line[last_match..])?;
+    Ok(())
+}
+pub fn run_replace(args: &ReplaceArgs, journal: &Journal) -> anyhow::Result<()> {
+    let replacer = Replacer::from_args(args)?;
+    let op = journal.start();
+
+    // Writes one file, after its backup when asked; both can be undone.
+    let save = |path: &Path, new_contents: &[u8]| -> anyhow::Result<()> {
+        if args.backup {
+            let backup_path = backup_path(path);
+            op.write(&backup_path, || {
+                fs::copy(path, &backup_path)
+                    .with_context(|| format!("Failed to create backup for {}", path.display()))
+            })?;
+        }
+        op.write(path, || {
+            write_atomic(path, |file| Ok(file.write_all(new_contents)?))
+        })
     };
 
-    let length = if file_type.is_file() {
-        md.len().to_string()
+    let mut builder = WalkBuilder::new(&args.path);
+    builder.hidden(true).ignore(false);
+
+    let color_choice = if io::stdout().is_terminal() {
+        ColorChoice::Always
     } else {
-        String::from("")
+        ColorChoice::Never
     };
+    let stdout = BufferWriter::stdout(color_choice);
+    let stdin = io::stdin();
+    let mut review = Review::default();
+    let mut files = 0;
+    let mut total = 0;
 
-    let name = entry.file_name().to_string_lossy().to_string();
-    println!("{:<10} {:<20} {:<8} {}", mode, datetime_str, length, name);
+    let skipped = AtomicUsize::new(0);
+
+    // A binary file has to stop the run before anything is written, not
+    // halfway through, so every file is classified first.
+    if args.binary == BinaryMode::Error {
+        walk_files_parallel(
+            &mut builder,
+            &args.parallel,
+            |path| {
+                let mut head = Vec::new();
+                File::open(path)
+                    .and_then(|file| file.take(READ_BUFFER_SIZE as u64).read_to_end(&mut head))
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                read_as_text(path, &head, args.binary, &skipped)?;
+                Ok(None::<()>)
+            },
+            |_, _| Ok(()),
+        )?;
+    }
 
-    Ok(())
-}
+    walk_files_parallel(
+        &mut builder,
+        &args.parallel,
+        |path| {
+            let contents =
+                fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
 
-pub fn remove(args: &RemoveArgs) -> anyhow::Result<()> {
-    let path = Path::new(&args.path);
+            let head = &contents[..contents.len().min(READ_BUFFER_SIZE)];
+            if !read_as_text(path, head, args.binary, &skipped)? {
+                return Ok(None);
+            }
 
-    if !path.exists() {
-        return Err(anyhow::anyhow!(
-            "Path '{}' does not exist.",
-            path.display()
-        ));
-    }
+            let edits = replacer.find(&contents);
+            if edits.is_empty() {
+                return Ok(None);
+            }
 
-    if path.is_file() {
-        match fs::remove_file(path) {
-            Ok(_) => {
-                println!("Remove '{}' success", path.display())
+            // Previews and questions are left to `emit`, one file at a time.
+            if args.dry_run || args.interactive {
+                return Ok(Some(Pending::Review(contents, edits)));
             }
-            Err(e) => {
-                return match e.kind() {
-                    ErrorKind::PermissionDenied => Err(anyhow::anyhow!(
-                        "Permission denied when remove '{}'.",
+
+            save(path, &apply_edits(&contents, &edits))?;
+
+            Ok(Some(Pending::Done(edits.len())))
+        },
+        |path, pending| {
+            let (contents, edits) = match pending {
+                Pending::Done(replaced) => {
+                    println!(
+                        "Replaced {} occurrence(s) of '{}' in {}",
+                        replaced,
+                        args.old_string,
                         path.display()
-                    )),
-                    _ => Err(anyhow::anyhow!(
-                        "Error when removing file '{}': {}",
-                        path.display(),
-                        e
-                    )),
+                    );
+                    files += 1;
+                    total += replaced;
+                    return Ok(());
                 }
-            }
-        };
-        return Ok(());
+                Pending::Review(contents, edits) => (contents, edits),
+            };
+
+            let hunks = diff_hunks(&contents, &edits, DIFF_CONTEXT);
+            let mut buffer = stdout.buffer();
+            write_diff_header(&mut buffer, path)?;
+
+            if args.dry_run {
+                for hunk in &hunks {
+                    write_hunk(&mut buffer, hunk)?;
+                }
+                writeln!(
+                    buffer,
+                    "{}: would replace {} occurrence(s)",
+                    path.display(),
+                    edits.len()
+                )?;
+                stdout.print(&buffer)?;
+                files += 1;
+                total += edits.len();
+                return Ok(());
+            }
+
+            if review.quit {
+                return Ok(());
+            }
+            stdout.print(&buffer)?;
+
+            let mut accepted = Vec::new();
+            for hunk in &hunks {
+                if !review.all {
+                    let mut buffer = stdout.buffer();
+                    write_hunk(&mut buffer, hunk)?;
+                    stdout.print(&buffer)?;
+                }
+                if review.accepts(&mut stdin.lock())? {
+                    accepted.extend_from_slice(&edits[hunk.edits.clone()]);
+                }
+                if review.quit {
+                    break;
+                }
+            }
+            if accepted.is_empty() {
+                return Ok(());
+            }
+
+            save(path, &apply_edits(&contents, &accepted))?;
+            println!(
+                "Replaced {} occurrence(s) of '{}' in {}",
+                accepted.len(),
+                args.old_string,
+                path.display()
+            );
+            files += 1;
+            total += accepted.len();
+            Ok(())
+        },
+    )?;
+
+    if args.dry_run {
+        println!("Would replace {} occurrence(s) in {} file(s)", total, files);
+    } else {
+        println!("Replaced {} occurrence(s) in {} file(s)", total, files);
+    }
+
+    report_skipped_binary(&skipped);
+    Ok(())
+}
+
+/// `notes.txt` is backed up as `notes.txt.bak`, so `notes.md` cannot collide with it.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+/// Lines of context around each change in `replace` diffs.
+const DIFF_CONTEXT: usize = 3;
+
+/// A file `replace` has looked at: either already written, with the number
+/// of replacements, or waiting to be previewed or reviewed.
+enum Pending {
+    Done(usize),
+    Review(Vec<u8>, Vec<Edit>),
+}
+
+/// The answers given so far with `replace --interactive`.
+#[derive(Default)]
+pub(crate) struct Review {
+    /// `a`: every hunk from here on is applied without asking.
+    pub all: bool,
+    /// `q`: nothing more is applied.
+    pub quit: bool,
+}
+
+impl Review {
+    /// Asks whether to apply the hunk just shown, until `input` gives a
+    /// valid answer. End of input counts as `q`.
+    pub(crate) fn accepts(&mut self, input: &mut impl BufRead) -> anyhow::Result<bool> {
+        if self.all {
+            return Ok(true);
+        }
+
+        loop {
+            print!("Apply this hunk [y,n,a,q]? ");
+            io::stdout().flush()?;
+
+            let mut answer = String::new();
+            if input.read_line(&mut answer)? == 0 {
+                println!();
+                self.quit = true;
+                return Ok(false);
+            }
+
+            match answer.trim() {
+                "y" => return Ok(true),
+                "n" => return Ok(false),
+                "a" => {
+                    self.all = true;
+                    return Ok(true);
+                }
+                "q" => {
+                    self.quit = true;
+                    return Ok(false);
+                }
+                _ => println!("y - apply this hunk, n - skip it, a - apply it and all remaining hunks, q - stop"),
+            }
+        }
+    }
+}
+
+/// What `replace` does to the contents of a file: replaces OLD_STRING, as a
+/// regex or literally, within `--lines` and at most `--max-replacements` times.
+pub(crate) struct Replacer {
+    regex: regex::bytes::Regex,
+    replacement: Vec<u8>,
+    expand: bool,
+    max: Option<usize>,
+    lines: Option<LineRange>,
+}
+
+/// One replacement: bytes `start..end` of a file become `replacement`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub replacement: Vec<u8>,
+}
+
+impl Replacer {
+    pub(crate) fn from_args(args: &ReplaceArgs) -> anyhow::Result<Replacer> {
+        let pattern = if args.regex {
+            args.old_string.clone()
+        } else {
+            regex::escape(&args.old_string)
+        };
+
+        // Files are matched as a whole, so `^` and `$` are made to match at
+        // every line like they would in an editor, CRLF line endings included.
+        let regex = regex::bytes::RegexBuilder::new(&pattern)
+            .case_insensitive(args.case_insensitive)
+            .multi_line(true)
+            .crlf(true)
+            .build()
+            .with_context(|| format!("Invalid regex pattern: {}", args.old_string))?;
+
+        Ok(Replacer {
+            regex,
+            replacement: args.new_string.as_bytes().to_vec(),
+            expand: args.regex,
+            max: args.max_replacements,
+            lines: args.lines,
+        })
+    }
+
+    /// The replacements to make in `contents`, in order.
+    pub(crate) fn find(&self, contents: &[u8]) -> Vec<Edit> {
+        let (from, to) = match self.lines {
+            Some(range) => line_span(contents, range),
+            None => (0, contents.len()),
+        };
+
+        // Only the lines of the range are searched, so a match cannot start
+        // before them or run past them. They start and end at line breaks or
+        // the ends of the file, so `^`, `$` and `\b` mean the same as in the
+        // whole file.
+        let text = &contents[from..to];
+        // After a final line break, the end of `text` starts the next line
+        // rather than ending one, and an empty match there is left out.
+        let end_is_line = !text.is_empty() && !text.ends_with(b"\n");
+        let mut edits = Vec::new();
+        for caps in self.regex.captures_iter(text) {
+            let whole = match caps.get(0) {
+                Some(whole) => whole,
+                None => continue,
+            };
+            if whole.start() == text.len() && !end_is_line
+                || self.max.is_some_and(|max| edits.len() >= max)
+            {
+                break;
+            }
+
+            let mut replacement = Vec::new();
+            if self.expand {
+                caps.expand(&self.replacement, &mut replacement);
+            } else {
+                replacement.extend_from_slice(&self.replacement);
+            }
+            edits.push(Edit {
+                start: from + whole.start(),
+                end: from + whole.end(),
+                replacement,
+            });
+        }
+        edits
+    }
+}
+
+/// Makes `edits`, which are in order and do not overlap, to `contents`.
+pub(crate) fn apply_edits(contents: &[u8], edits: &[Edit]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(contents.len());
+    let mut last = 0;
+    for edit in edits {
+        out.extend_from_slice(&contents[last..edit.start]);
+        out.extend_from_slice(&edit.replacement);
+        last = edit.end;
+    }
+    out.extend_from_slice(&contents[last..]);
+    out
+}
+
+/// Where each line of `contents` starts, plus `contents.len()` when it ends
+/// with a newline.
+fn line_starts(contents: &[u8]) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(
+            contents
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| b == b'\n')
+                .map(|(i, _)| i + 1),
+        )
+        .collect()
+}
+
+/// Byte offsets where the lines of `range` start and end in `contents`,
+/// the newline of the last line included.
+fn line_span(contents: &[u8], range: LineRange) -> (usize, usize) {
+    let starts = line_starts(contents);
+    let line_start = |line: usize| starts.get(line - 1).copied().unwrap_or(contents.len());
+
+    let from = line_start(range.start);
+    let to = range.end.map_or(contents.len(), |end| line_start(end + 1));
+    (from, to)
+}
+
+/// One hunk of a unified diff. Line numbers count from 1.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Hunk {
+    /// The edits the hunk shows, as indices into the file's edits.
+    pub edits: Range<usize>,
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    /// Each line with its marker: ' ', '-' or '+'.
+    pub lines: Vec<(char, String)>,
+}
+
+/// Turns `edits` into unified diff hunks with `context` lines around each
+/// change. Edits touching the same lines form one change.
+pub(crate) fn diff_hunks(contents: &[u8], edits: &[Edit], context: usize) -> Vec<Hunk> {
+    let starts = line_starts(contents);
+    // Line (0-based) holding byte `offset`.
+    let line_of = |offset: usize| starts.partition_point(|&start| start <= offset) - 1;
+    let line_count = if contents.ends_with(b"\n") || contents.is_empty() {
+        starts.len() - 1
+    } else {
+        starts.len()
+    };
+    let line_text = |line: usize| {
+        let end = starts.get(line + 1).copied().unwrap_or(contents.len());
+        let text = &contents[starts[line]..end];
+        String::from_utf8_lossy(text.strip_suffix(b"\n").unwrap_or(text)).into_owned()
+    };
+
+    // Changes as (first line, last line, edit indices).
+    let mut changes: Vec<(usize, usize, Range<usize>)> = Vec::new();
+    for (i, edit) in edits.iter().enumerate() {
+        let first = line_of(edit.start);
+        let last = if edit.end > edit.start {
+            line_of(edit.end - 1)
+        } else {
+            first
+        };
+        match changes.last_mut() {
+            Some(change) if first <= change.1 => {
+                change.1 = change.1.max(last);
+                change.2.end = i + 1;
+            }
+            _ => changes.push((first, last, i..i + 1)),
+        }
+    }
+
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut grouped: Vec<Vec<(usize, usize, Range<usize>)>> = Vec::new();
+    for change in changes {
+        match grouped.last_mut() {
+            Some(group) if change.0 - group[group.len() - 1].1 <= 2 * context + 1 => {
+                group.push(change)
+            }
+            _ => grouped.push(vec![change]),
+        }
+    }
+
+    // How many lines the hunks so far added or removed.
+    let mut shift: isize = 0;
+    for group in grouped {
+        let first = group[0].0.saturating_sub(context);
+        let last = (group[group.len() - 1].1 + context).min(line_count.max(1) - 1);
+
+        let mut lines = Vec::new();
+        let mut line = first;
+        for (change_first, change_last, range) in &group {
+            lines.extend((line..*change_first).map(|l| (' ', line_text(l))));
+
+            let start = starts[*change_first];
+            let end = starts
+                .get(change_last + 1)
+                .copied()
+                .unwrap_or(contents.len());
+            let shifted: Vec<Edit> = edits[range.clone()]
+                .iter()
+                .map(|edit| Edit {
+                    start: edit.start - start,
+                    end: edit.end - start,
+                    replacement: edit.replacement.clone(),
+                })
+                .collect();
+            let new_text = apply_edits(&contents[start..end], &shifted);
+
+            lines.extend((*change_first..=*change_last).map(|l| ('-', line_text(l))));
+            lines.extend(
+                String::from_utf8_lossy(&new_text)
+                    .split_inclusive('\n')
+                    .map(|l| ('+', l.strip_suffix('\n').unwrap_or(l).to_string())),
+            );
+            line = change_last + 1;
+        }
+        lines.extend((line..=last).map(|l| (' ', line_text(l))));
+
+        let old_len = lines.iter().filter(|(marker, _)| *marker != '+').count();
+        let new_len = lines.iter().filter(|(marker, _)| *marker != '-').count();
+        hunks.push(Hunk {
+            edits: group[0].2.start..group[group.len() - 1].2.end,
+            old_start: first + 1,
+            old_len,
+            new_start: (first as isize + 1 + shift) as usize,
+            new_len,
+            lines,
+        });
+        shift += new_len as isize - old_len as isize;
+    }
+    hunks
+}
+
+fn write_diff_header(out: &mut impl WriteColor, path: &Path) -> io::Result<()> {
+    out.set_color(ColorSpec::new().set_bold(true))?;
+    writeln!(out, "--- a/{}", path.display())?;
+    writeln!(out, "+++ b/{}", path.display())?;
+    out.reset()
+}
+
+pub(crate) fn write_hunk(out: &mut impl WriteColor, hunk: &Hunk) -> io::Result<()> {
+    out.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)))?;
+    writeln!(
+        out,
+        "@@ -{},{} +{},{} @@",
+        hunk.old_start, hunk.old_len, hunk.new_start, hunk.new_len
+    )?;
+    out.reset()?;
+
+    for (marker, text) in &hunk.lines {
+        match marker {
+            '-' => out.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?,
+            '+' => out.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?,
+            _ => {}
+        }
+        write!(out, "{}{}", marker, text)?;
+        out.reset()?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+pub fn run_count(args: &CountArgs) -> anyhow::Result<()> {
+    let regex = if args.regex {
+        Regex::new(&args.pattern)?
+    } else {
+        Regex::new(&regex::escape(&args.pattern))?
+    };
+
+    let mut builder = WalkBuilder::new(&args.path);
+    builder.hidden(true).ignore(false);
+
+    let mut total = 0;
+    let skipped = AtomicUsize::new(0);
+
+    walk_files_parallel(
+        &mut builder,
+        &args.parallel,
+        |path| {
+            let file = File::open(path)?;
+            let mut reader = BufReader::with_capacity(READ_BUFFER_SIZE, file);
+            if !read_as_text(path, reader.fill_buf()?, args.binary, &skipped)? {
+                return Ok(None);
+            }
+            let mut count = 0;
+
+            for_each_line(reader, |_, line| {
+                if regex.is_match(line) {
+                    count += 1;
+                }
+                Ok(true)
+            })?;
+
+            Ok(if count > 0 { Some(count) } else { None })
+        },
+        |path, count| {
+            println!("{}: {}", path.display(), count);
+            total += count;
+            Ok(())
+        },
+    )?;
+
+    println!("Total matches across all files: {}", total);
+    report_skipped_binary(&skipped);
+    Ok(())
+}
+
+pub fn rename(args: &RenameArgs, journal: &Journal) -> anyhow::Result<()> {
+    if let (Some(from), Some(to)) = (&args.from, &args.to) {
+        return crate::rename::run_batch(args, from, to, journal);
+    }
+    if args.edit {
+        return crate::rename::run_edit(args, journal);
+    }
+
+    let new_file_name = args.new_file_name.as_deref().unwrap_or_default();
+    let path = Path::new(&args.old_file_name);
+    let new_path = Path::new(new_file_name);
+
+    // `fs::rename` would silently replace an existing file.
+    if fs::symlink_metadata(new_path).is_ok() {
+        eprintln!("Error: Destination '{}' already exists.", new_file_name);
+        return Ok(());
+    }
+
+    let op = journal.start();
+    match op.rename(path, new_path, || Ok(fs::rename(path, new_path)?)) {
+        Ok(_) => {
+            println!(
+                "Successfully renamed '{}' to '{}'",
+                &args.old_file_name, new_file_name
+            );
+        }
+        Err(e) => match e.downcast_ref::<io::Error>().map(|err| err.kind()) {
+            Some(ErrorKind::NotFound) => {
+                eprintln!("Error: Source file '{}' not found.", &args.old_file_name)
+            }
+
+            Some(ErrorKind::AlreadyExists) => {
+                eprintln!("Error: Destination '{}' already exists.", new_file_name);
+            }
+            Some(ErrorKind::PermissionDenied) => {
+                eprintln!(
+                    "Error: Permission denied when renaming '{}'.",
+                    &args.old_file_name
+                );
+            }
+            _ => eprintln!(
+                "Error:
+                Failed to rename '{}': {}",
+                &args.old_file_name, new_file_name
+            ),
+        },
+    }
+
+    Ok(())
+}
+
+pub fn list_current_dir() -> anyhow::Result<()> {
+    // Read the entries of the current directory
+    let entries = fs::read_dir(".")?;
+
+    // Print a header
+    println!(
+        "{:<10} {:<20} {:<8} Name",
+        "Mode", "LastWriteTime", "Length"
+    );
+    println!("{:-<10} {:-<20} {:-<8} {:-<1}", "", "", "", "");
+
+    for entry_result in entries {
+        let entry = entry_result?;
+        let md = entry.metadata()?;
+        print_entry(&entry, &md)?;
+    }
+
+    Ok(())
+}
+
+fn print_entry(entry: &DirEntry, md: &Metadata) -> io::Result<()> {
+    let file_type = md.file_type();
+    let mode = if file_type.is_dir() {
+        "d-----"
+    } else {
+        "-a----"
+    };
+
+    let datetime_str = match md.modified() {
+        Ok(time) => format_system_time(time),
+        Err(_) => String::from("Unknown"),
+    };
+
+    let length = if file_type.is_file() {
+        md.len().to_string()
+    } else {
+        String::from("")
+    };
+
+    let name = entry.file_name().to_string_lossy().to_string();
+    println!("{:<10} {:<20} {:<8} {}", mode, datetime_str, length, name);
+
+    Ok(())
+}
+
+pub fn remove(args: &RemoveArgs, journal: &Journal) -> anyhow::Result<()> {
+    let paths = expand_remove_path(&args.path)?;
+
+    if paths.is_empty() {
+        if args.force {
+            return Ok(());
+        }
+        return Err(anyhow::anyhow!("Path '{}' does not exist.", args.path));
+    }
+
+    let mut targets = Vec::new();
+    for path in &paths {
+        targets.extend(plan_removal(path, args)?);
+    }
+
+    let total_files: u64 = targets.iter().map(|t| t.files).sum();
+    let total_dirs: u64 = targets.iter().map(|t| t.dirs).sum();
+    let total_size: u64 = targets.iter().map(|t| t.size).sum();
+
+    if args.dry_run {
+        for target in &targets {
+            if target.is_dir {
+                println!(
+                    "Would remove dir '{}' ({} files, {} dirs, {})",
+                    target.path.display(),
+                    target.files,
+                    target.dirs,
+                    format_size(target.size, true)
+                );
+            } else {
+                println!(
+                    "Would remove file '{}' ({})",
+                    target.path.display(),
+                    format_size(target.size, true)
+                );
+            }
+        }
+        println!(
+            "Total: {} files, {} dirs, {}",
+            total_files,
+            total_dirs,
+            format_size(total_size, true)
+        );
+        return Ok(());
+    }
+
+    if args.permanent
+        && !args.force
+        && targets.iter().any(|t| t.is_dir)
+        && !confirm(&format!(
+            "Permanently delete {} files and {} dirs ({})? (y/n): ",
+            total_files,
+            total_dirs,
+            format_size(total_size, true)
+        ))?
+    {
+        return Err(anyhow::anyhow!("Error: Remove aborted."));
+    }
+
+    let trash = (!args.permanent).then(|| journal.trash());
+
+    // What `--permanent` deletes is not kept, so it is not recorded either:
+    // there is nothing `undo` could bring back.
+    let op = journal.start();
+    for target in &targets {
+        match discard(&target.path, trash) {
+            Ok(id) => {
+                if let Some(id) = &id {
+                    op.trashed(&target.path, id)?;
+                }
+                print_removed(&target.path, id.as_deref());
+            }
+            Err(e) => {
+                let kind = e.downcast_ref::<io::Error>().map(|err| err.kind());
+                return match kind {
+                    Some(ErrorKind::PermissionDenied) => Err(anyhow::anyhow!(
+                        "Permission denied when remove '{}'.",
+                        target.path.display()
+                    )),
+                    _ => Err(anyhow::anyhow!(
+                        "Error when removing '{}': {}",
+                        target.path.display(),
+                        e
+                    )),
+                };
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A single path `remove` is going to discard, with what it contains.
+#[derive(Debug)]
+pub(crate) struct RemovalTarget {
+    pub(crate) path: PathBuf,
+    pub(crate) is_dir: bool,
+    pub(crate) files: u64,
+    pub(crate) dirs: u64,
+    pub(crate) size: u64,
+}
+
+fn expand_remove_path(input: &str) -> anyhow::Result<Vec<PathBuf>> {
+    if !input.contains(['*', '?', '[']) {
+        let path = PathBuf::from(input);
+        return Ok(if fs::symlink_metadata(&path).is_ok() {
+            vec![path]
+        } else {
+            Vec::new()
+        });
+    }
+
+    let mut paths = Vec::new();
+    for entry in glob::glob(input).with_context(|| format!("Invalid glob pattern: {}", input))? {
+        paths.push(entry?);
+    }
+    Ok(paths)
+}
+
+/// Works out what removing `path` means for the given flags:
+/// - a file is always removed as a whole;
+/// - `--recursive` removes a directory with everything in it;
+/// - `--files-only` removes every file below a directory, keeping the tree;
+/// - `--dirs-only` removes every subdirectory that contains no files at any
+///   depth, reported as the topmost such directories.
+pub(crate) fn plan_removal(path: &Path, args: &RemoveArgs) -> anyhow::Result<Vec<RemovalTarget>> {
+    if !path.is_dir() || path.is_symlink() {
+        let size = fs::symlink_metadata(path)?.len();
+        return Ok(vec![RemovalTarget {
+            path: path.to_path_buf(),
+            is_dir: false,
+            files: 1,
+            dirs: 0,
+            size,
+        }]);
+    }
+
+    if args.recursive {
+        let (files, dirs, size) = dir_stats(path)?;
+        return Ok(vec![RemovalTarget {
+            path: path.to_path_buf(),
+            is_dir: true,
+            files,
+            dirs: dirs + 1,
+            size,
+        }]);
+    }
+
+    if args.files_only {
+        let mut targets = Vec::new();
+        for entry in WalkDir::new(path).min_depth(1) {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                targets.push(RemovalTarget {
+                    path: entry.path().to_path_buf(),
+                    is_dir: false,
+                    files: 1,
+                    dirs: 0,
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+        return Ok(targets);
+    }
+
+    if args.dirs_only {
+        // Every directory that has a file somewhere below it must stay.
+        let mut occupied = std::collections::HashSet::new();
+        let mut dirs = Vec::new();
+        for entry in WalkDir::new(path).min_depth(1) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                dirs.push(entry.path().to_path_buf());
+            } else {
+                for ancestor in entry.path().ancestors().skip(1) {
+                    if !occupied.insert(ancestor.to_path_buf()) || ancestor == path {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut targets = Vec::new();
+        for dir in dirs {
+            let parent_kept = dir
+                .parent()
+                .is_some_and(|parent| parent == path || occupied.contains(parent));
+            if !occupied.contains(&dir) && parent_kept {
+                let (_, dirs, _) = dir_stats(&dir)?;
+                targets.push(RemovalTarget {
+                    path: dir,
+                    is_dir: true,
+                    files: 0,
+                    dirs: dirs + 1,
+                    size: 0,
+                });
+            }
+        }
+        return Ok(targets);
+    }
+
+    Err(anyhow::anyhow!(
+        "Error: '{}' is a directory. Use '--recursive', '--files-only' or '--dirs-only'.",
+        path.display()
+    ))
+}
+
+/// Counts files, subdirectories and total file size below `path`.
+fn dir_stats(path: &Path) -> anyhow::Result<(u64, u64, u64)> {
+    let (mut files, mut dirs, mut size) = (0, 0, 0);
+    for entry in WalkDir::new(path).min_depth(1) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            dirs += 1;
+        } else {
+            files += 1;
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok((files, dirs, size))
+}
+
+/// Moves `path` to the trash when one is given, otherwise deletes it for good.
+/// Returns the trash id of the removed item.
+fn discard(path: &Path, trash: Option<&Trash>) -> anyhow::Result<Option<String>> {
+    match trash {
+        Some(trash) => Ok(Some(trash.put(path)?)),
+        None => {
+            if path.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+fn print_removed(path: &Path, trash_id: Option<&str>) {
+    match trash_id {
+        Some(id) => println!("Moved '{}' to trash (id: {})", path.display(), id),
+        None => println!("Remove '{}' success", path.display()),
+    }
+}
+
+pub fn copy(args: &CopyArgs, journal: &Journal) -> anyhow::Result<()> {
+    if !args.path_buf.exists() {
+        return Err(anyhow::anyhow!(
+            "Error: Source file {:?} does not exist.",
+            args.path_buf.display()
+        ));
+    }
+
+    let op = journal.start();
+
+    if args.path_buf.is_file() {
+        let copy_path = if args.copy_des.is_dir() {
+            match args.path_buf.file_name() {
+                Some(file_name) => args.copy_des.join(file_name),
+                None => {
+                    return Err(anyhow::anyhow!("Error: The source path has no file name."));
+                }
+            }
+        } else {
+            args.copy_des.clone()
+        };
+
+        if copy_path.exists() && !confirm_overwrite(&copy_path)? {
+            return Err(anyhow::anyhow!("Error: File copy aborted."));
+        }
+
+        op.write(&copy_path, || Ok(fs::copy(&args.path_buf, &copy_path)?))?;
+        println!(
+            "Copied file: {:?} -> {:?}",
+            args.path_buf.display(),
+            args.copy_des.display()
+        );
+    } else {
+        if args.recursive {
+            if let Err(err) = copy_recursive(&args.path_buf, &args.copy_des, &op) {
+                return Err(anyhow::anyhow!("Error: copying folder: {}", err));
+            }
+        } else {
+            return Err(anyhow::anyhow!(
+                "Error: '{:?}' is a directory. Use '-r' to copy recursively.",
+                args.path_buf
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn move_path(args: &MoveArgs, journal: &Journal) -> anyhow::Result<()> {
+    let src = &args.old_des;
+
+    if !src.exists() {
+        return Err(anyhow::anyhow!(
+            "Error: Source {:?} does not exist.",
+            src.display()
+        ));
+    }
+
+    if src.is_dir() && !args.recursive {
+        return Err(anyhow::anyhow!(
+            "Error: '{:?}' is a directory. Use '-r' to move recursively.",
+            src
+        ));
+    }
+
+    // Moving into an existing directory keeps the source name, like `mv`.
+    let dest = if args.new_des.is_dir() {
+        match src.file_name() {
+            Some(file_name) => args.new_des.join(file_name),
+            None => {
+                return Err(anyhow::anyhow!("Error: The source path has no file name."));
+            }
+        }
+    } else {
+        args.new_des.clone()
+    };
+
+    if dest.exists() {
+        if dest.is_dir() {
+            return Err(anyhow::anyhow!(
+                "Error: Destination {:?} is an existing directory.",
+                dest.display()
+            ));
+        }
+
+        if src.is_dir() {
+            return Err(anyhow::anyhow!(
+                "Error: Cannot overwrite file {:?} with a directory.",
+                dest.display()
+            ));
+        }
+
+        if !confirm_overwrite(&dest)? {
+            return Err(anyhow::anyhow!("Error: File move aborted."));
+        }
+    }
+
+    let op = journal.start();
+    op.rename(src, &dest, || match fs::rename(src, &dest) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => move_by_copy(src, &dest),
+        Err(e) => match e.kind() {
+            ErrorKind::PermissionDenied => Err(anyhow::anyhow!(
+                "Error: Permission denied when moving '{}'.",
+                src.display()
+            )),
+            _ => Err(anyhow::anyhow!(
+                "Error: Failed to move '{}': {}",
+                src.display(),
+                e
+            )),
+        },
+    })?;
+
+    println!("Moved: {:?} -> {:?}", src.display(), dest.display());
+    Ok(())
+}
+
+/// Writes `path` through a temp file in the same directory: `write` fills
+/// it, it is synced to disk and then renamed over `path`, so `path` is never
+/// seen half-written. A file being replaced keeps its permissions and, where
+/// the user may set it, its owner; a symlink is followed, not replaced.
+pub(crate) fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    write_atomic_with(path, true, write)
+}
+
+/// Like [`write_atomic`], but a symlink at `path` is replaced by the new
+/// file instead of followed, so what an archive extracts cannot land
+/// outside the output directory.
+pub(crate) fn write_atomic_no_follow(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    write_atomic_with(path, false, write)
+}
+
+fn write_atomic_with(
+    path: &Path,
+    follow_links: bool,
+    write: impl FnOnce(&mut File) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let original = if follow_links {
+        fs::metadata(path).ok()
+    } else {
+        fs::symlink_metadata(path).ok().filter(|md| md.is_file())
+    };
+    let path = if follow_links && original.is_some() {
+        fs::canonicalize(path)?
+    } else {
+        path.to_path_buf()
+    };
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".tmp-");
+    // A new file gets the same mode `File::create` would give it.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(fs::Permissions::from_mode(0o666));
+    }
+    let mut temp = builder
+        .tempfile_in(dir)
+        .with_context(|| format!("Failed to create a temp file in {}", dir.display()))?;
+
+    write(temp.as_file_mut())?;
+    temp.as_file().sync_all()?;
+
+    if let Some(original) = &original {
+        // Only root may hand a file to someone else; anyone else keeps
+        // owning what they write.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let _ =
+                std::os::unix::fs::chown(temp.path(), Some(original.uid()), Some(original.gid()));
+        }
+        fs::set_permissions(temp.path(), original.permissions())?;
+    }
+
+    temp.persist(&path)
+        .map_err(|e| e.error)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    // Make the rename itself survive a crash.
+    #[cfg(unix)]
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Fallback for `move_path` when `fs::rename` cannot cross filesystems:
+/// copy everything to `dest` first and only delete `src` once that succeeded.
+pub(crate) fn move_by_copy(src: &Path, dest: &Path) -> anyhow::Result<()> {
+    if src.is_dir() {
+        copy_dir_all(src, dest, None)
+            .with_context(|| format!("Failed to copy {} to {}", src.display(), dest.display()))?;
+        fs::remove_dir_all(src).with_context(|| format!("Failed to remove {}", src.display()))?;
+    } else {
+        fs::copy(src, dest)
+            .with_context(|| format!("Failed to copy {} to {}", src.display(), dest.display()))?;
+        fs::remove_file(src).with_context(|| format!("Failed to remove {}", src.display()))?;
+    }
+    Ok(())
+}
+
+fn confirm_overwrite(path: &Path) -> io::Result<bool> {
+    confirm(&format!(
+        "File {:?} already exists. Overwrite? (y/n): ",
+        path.display()
+    ))
+}
+
+fn confirm(prompt: &str) -> io::Result<bool> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_lowercase() == "y")
+}
+
+pub fn read_csv_table(args: ReadTableCsvArgs) -> anyhow::Result<()> {
+    check_data_file(&args.path, "CSV", is_csv_kind, is_csv(&args.path))?;
+
+    let csv_file = CsvFile {
+        file_path: args.path,
+        has_headers: true,
+        skip: if args.start { args.row_num } else { 0 },
+        limit: if args.limit { args.limit_row } else { 1000 },
+    };
+
+    println!("{:?}", args.limit);
+
+    match csv_file.read_file() {
+        Ok(_) => {
+            println!("File successfully read.");
+        }
+        Err(e) => {
+            if let Some(io_err) = e.downcast_ref::<io::Error>() {
+                match io_err.kind() {
+                    ErrorKind::NotFound => {
+                        eprintln!("Error: File not found.");
+                    }
+                    ErrorKind::PermissionDenied => {
+                        eprintln!("Error: Permission denied.");
+                    }
+                    other_kind => {
+                        eprintln!("Error: Some other I/O error: {:?}", other_kind);
+                    }
+                }
+            } else {
+                eprintln!("Error: Some other error: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn decompress_zip(args: &DecompressArgs, journal: &Journal) -> anyhow::Result<()> {
+    let path = &args.path;
+
+    if !path.exists() {
+        return Err(anyhow::anyhow!("Error: File {:?} does not exists.", path));
+    }
+
+    if args.stdout {
+        return write_entry(args, &mut io::stdout().lock());
+    }
+
+    let file = File::open(path)?;
+
+    let mut archive = ZipArchive::new(file)?;
+    let policy = OverwritePolicy::from_args(args);
+    let filter = EntryFilter::from_args(args)?;
+
+    // Validate every selected name up front so a bad entry never leaves a
+    // half-extracted archive.
+    let mut entry_paths = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let file = archive.by_index(i)?;
+        if !filter.matches(file.name()) {
+            entry_paths.push(None);
+            continue;
+        }
+        let entry_path = match file.enclosed_name() {
+            Some(name) => name.to_path_buf(),
+            None => sanitize_entry_path(Path::new(file.name()), args.strip_unsafe)?,
+        };
+        entry_paths.push(Some(entry_path));
+    }
+
+    let output_dir = args
+        .output_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    let op = journal.start();
+    op.create_dir_all(&output_dir)?;
+    let mut dir_attrs = Vec::new();
+
+    for (i, entry_path) in entry_paths.into_iter().enumerate() {
+        let entry_path = match entry_path {
+            Some(entry_path) if !entry_path.as_os_str().is_empty() => entry_path,
+            _ => continue,
+        };
+        let mut file = archive.by_index(i)?;
+
+        let outpath = output_dir.join(entry_path);
+        let mtime = zip_time_to_system_time(file.last_modified());
+
+        if file.is_dir() {
+            op.create_dir_all(&outpath)?;
+            dir_attrs.push((outpath, mtime, file.unix_mode()));
+        } else {
+            if let Some(parent) = outpath.parent() {
+                if !parent.exists() {
+                    op.create_dir_all(parent)?;
+                }
+            }
+
+            let outpath = match resolve_output_path(outpath, policy)? {
+                Some(outpath) => outpath,
+                None => continue,
+            };
+
+            op.write(&outpath, || {
+                write_atomic_no_follow(&outpath, |outfile| {
+                    io::copy(&mut file, outfile)?;
+                    if let Some(mtime) = mtime {
+                        outfile.set_modified(mtime)?;
+                    }
+                    Ok(())
+                })
+            })?;
+            set_unix_mode(&outpath, file.unix_mode())?;
+            println!("outpath: {:?}", outpath);
+        }
+    }
+
+    // Directories last: writing files into them would bump their mtime, and
+    // a read-only mode would stop the files from being written at all.
+    for (dir, mtime, mode) in dir_attrs.into_iter().rev() {
+        if let Some(mtime) = mtime {
+            set_dir_modified(&dir, mtime)?;
+        }
+        set_unix_mode(&dir, mode)?;
+    }
+
+    println!("Decompress {} successfully", path.display());
+    Ok(())
+}
+
+/// What to do when an extracted file already exists on disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OverwritePolicy {
+    Prompt,
+    Skip,
+    Overwrite,
+    Rename,
+}
+
+impl OverwritePolicy {
+    fn from_args(args: &DecompressArgs) -> Self {
+        if args.skip_existing {
+            OverwritePolicy::Skip
+        } else if args.overwrite {
+            OverwritePolicy::Overwrite
+        } else if args.rename {
+            OverwritePolicy::Rename
+        } else {
+            OverwritePolicy::Prompt
+        }
+    }
+}
+
+/// Applies `policy` to an output path. Returns `None` when the entry should be skipped.
+fn resolve_output_path(path: PathBuf, policy: OverwritePolicy) -> anyhow::Result<Option<PathBuf>> {
+    if fs::symlink_metadata(&path).is_err() {
+        return Ok(Some(path));
+    }
+
+    if path.is_dir() {
+        return Err(anyhow::anyhow!(
+            "Error: Cannot extract over directory {:?}.",
+            path.display()
+        ));
+    }
+
+    let replace = match policy {
+        OverwritePolicy::Overwrite => true,
+        OverwritePolicy::Rename => return Ok(Some(unique_path(&path))),
+        OverwritePolicy::Skip => false,
+        OverwritePolicy::Prompt => confirm_overwrite(&path)?,
+    };
+    if !replace {
+        println!("Skipped existing: {:?}", path.display());
+        return Ok(None);
+    }
+
+    Ok(Some(path))
+}
+
+/// Returns the first free `name (N).ext` next to `path`.
+fn unique_path(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut n = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{} ({}){}", stem, n, ext));
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Turns an archive entry name into a relative path that stays inside the
+/// output directory. Absolute paths and `..` are refused unless `strip` is set,
+/// in which case those components are dropped.
+pub(crate) fn sanitize_entry_path(name: &Path, strip: bool) -> anyhow::Result<PathBuf> {
+    use std::path::Component;
+
+    let mut clean = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                if !strip {
+                    return Err(anyhow::anyhow!(
+                        "Error: Unsafe entry path {:?} in archive. Use --strip-unsafe to extract it anyway.",
+                        name.display()
+                    ));
+                }
+            }
+        }
+    }
+    Ok(clean)
+}
+
+pub(crate) fn zip_time_to_system_time(time: zip::DateTime) -> Option<SystemTime> {
+    use chrono::{Local, TimeZone};
+
+    // DOS timestamps carry no zone; archivers write local time.
+    Local
+        .with_ymd_and_hms(
+            time.year() as i32,
+            time.month() as u32,
+            time.day() as u32,
+            time.hour() as u32,
+            time.minute() as u32,
+            time.second() as u32,
+        )
+        .single()
+        .map(SystemTime::from)
+}
+
+#[cfg(unix)]
+fn set_unix_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match mode {
+        // Setuid, setgid and sticky bits from an archive are not to be trusted.
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_unix_mode(_path: &Path, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+fn set_dir_modified(dir: &Path, mtime: SystemTime) -> io::Result<()> {
+    match File::open(dir) {
+        Ok(handle) => handle.set_modified(mtime),
+        // Not every platform lets a directory be opened as a file.
+        Err(_) => Ok(()),
+    }
+}
+
+/// Extracts whatever `args.path` holds, recognised by its magic bytes
+/// rather than its extension.
+pub fn decompress(args: &DecompressArgs, journal: &Journal) -> anyhow::Result<()> {
+    let path = &args.path;
+
+    if !path.exists() {
+        return Err(anyhow::anyhow!("Error: File {:?} does not exists.", path));
     }
 
-    if path.is_dir() {
-        // only remove file
-        if args.option == "-f" {
-            remove_all_files_in_dir(path)
-                .expect(format!("Failed to remove '{}'", path.display()).as_str());
-        }
+    match detect_format(path)? {
+        Format::Zip => decompress_zip(args, journal),
+        Format::Tar(codec) => decompress_tar(args, codec, journal),
+        Format::Stream(Codec::None) => Err(anyhow::anyhow!(
+            "Error: {} is not an archive or a compressed file.",
+            path.display()
+        )),
+        Format::Stream(codec) => decompress_stream(args, codec, journal),
+    }
+}
 
-        // only remove folder
-        if args.option == "-r" {
-            remove_all_dir_in_dir(path)
-                .expect(format!("Failed to remove '{}'", path.display()).as_str());
-        }
+fn decompress_tar(args: &DecompressArgs, codec: Codec, journal: &Journal) -> anyhow::Result<()> {
+    let path = &args.path;
+    let filter = EntryFilter::from_args(args)?;
 
-        // remove file and folder
-        if args.option == "-rf" {
-            match fs::remove_dir_all(path) {
-                Ok(_) => {
-                    println!("Remove '{}' success", path.display())
-                }
-                Err(e) => {
-                    return Err(anyhow::anyhow!(
-                        "Error: Failed to remove '{}': {}",
-                        path.display(),
-                        e
-                    ));
-                }
-            }
-            return Ok(());
-        }
+    if args.stdout {
+        return write_entry(args, &mut io::stdout().lock());
     }
 
+    // First pass only checks names, so a bad entry is refused before anything is written.
+    validate_tar_entries(open_decoded(path, codec)?, &filter, args.strip_unsafe)?;
+
+    let output_dir = args
+        .output_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."));
+    unpack_tar(
+        open_decoded(path, codec)?,
+        &output_dir,
+        &filter,
+        args,
+        journal,
+    )?;
+    println!("Decompress {} successfully", path.display());
     Ok(())
 }
 
-pub fn copy(args: &CopyArgs) -> anyhow::Result<()> {
-    let mut copy_path = PathBuf::new();
-
-    if !args.path_buf.exists() {
-        return Err(anyhow::anyhow!(
-            "Error: Source file {:?} does not exist.",
-            args.path_buf.display()
-        ));
+/// A single compressed file is written next to the input, minus the codec's suffix.
+fn decompress_stream(args: &DecompressArgs, codec: Codec, journal: &Journal) -> anyhow::Result<()> {
+    let path = &args.path;
+    if args.stdout {
+        return write_entry(args, &mut io::stdout().lock());
     }
 
-    if args.path_buf.is_file() {
-        if args.copy_des.is_file() {
-            if args.copy_des.exists() {
-                print!(
-                    "File {:?} already exists. Overwrite? (y/n): ",
-                    args.copy_des.display()
-                );
-                io::stdout().flush()?;
+    let mut decoder = open_decoded(path, codec)?;
 
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                let input = input.trim().to_lowercase();
+    let output_dir = match &args.output_dir {
+        Some(dir) => dir.clone(),
+        // A bare file name has the current dir as its parent.
+        None => match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => PathBuf::from("."),
+            parent => parent.map(Path::to_path_buf).ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "Path has no parent directory")
+            })?,
+        },
+    };
+    let op = journal.start();
+    op.create_dir_all(&output_dir)?;
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid file name"))?
+        .to_string_lossy();
+    let out_name = codec
+        .suffixes()
+        .iter()
+        .find_map(|suffix| file_name.strip_suffix(suffix))
+        .filter(|stem| !stem.is_empty())
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}.out", file_name));
+
+    let out_path =
+        match resolve_output_path(output_dir.join(out_name), OverwritePolicy::from_args(args))? {
+            Some(out_path) => out_path,
+            None => return Ok(()),
+        };
 
-                if input != "y" {
-                    return Err(anyhow::anyhow!("Error: File copy aborted."));
-                }
-            }
+    op.write(&out_path, || {
+        write_atomic_no_follow(&out_path, |out_file| {
+            io::copy(&mut decoder, out_file)?;
+            Ok(())
+        })
+    })?;
+    println!("Decompress {} to {}", path.display(), out_path.display());
+    Ok(())
+}
 
-            copy_path = PathBuf::from(&args.copy_des);
-        }
+/// Include/exclude globs picking which archive entries to extract.
+pub(crate) struct EntryFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
 
-        if args.copy_des.is_dir() && args.copy_des.exists() {
-            let copy_path = match args.path_buf.file_name() {
-                Some(file_name) => args.copy_des.join(file_name),
-                None => {
-                    return Err(anyhow::anyhow!("Error: The source path has no file name."));
-                }
-            };
+impl EntryFilter {
+    pub(crate) fn from_args(args: &DecompressArgs) -> anyhow::Result<Self> {
+        let compile = |patterns: &[String]| -> anyhow::Result<Vec<glob::Pattern>> {
+            patterns
+                .iter()
+                .map(|p| {
+                    glob::Pattern::new(p).with_context(|| format!("Invalid glob pattern: {}", p))
+                })
+                .collect()
+        };
 
-            if copy_path.exists() {
-                print!(
-                    "File {:?} already exists. Overwrite? (y/n): ",
-                    args.copy_des.display()
-                );
-                io::stdout().flush()?;
+        Ok(EntryFilter {
+            include: compile(&args.include)?,
+            exclude: compile(&args.exclude)?,
+        })
+    }
 
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                let input = input.trim().to_lowercase();
+    /// `*` also crosses `/`, so `*.toml` picks config files at any depth.
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        let name = name.trim_start_matches("./");
+        let dir_name = name.trim_end_matches('/');
+
+        (self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|p| p.matches(name) || p.matches(dir_name)))
+            && !self
+                .exclude
+                .iter()
+                .any(|p| p.matches(name) || p.matches(dir_name))
+    }
+}
 
-                if input != "y" {
-                    eprintln!("Error: File copy aborted.");
-                    return Ok(());
-                }
-            }
-        }
+fn single_entry(mut selected: Vec<String>, archive: &Path) -> anyhow::Result<String> {
+    match selected.len() {
+        1 => Ok(selected.remove(0)),
+        0 => Err(anyhow::anyhow!(
+            "Error: No file entry in {} matches the selection.",
+            archive.display()
+        )),
+        n => Err(anyhow::anyhow!(
+            "Error: --stdout needs exactly one entry, but {} entries match: {}",
+            n,
+            selected.join(", ")
+        )),
+    }
+}
 
-        fs::copy(&args.path_buf, &copy_path)?;
-        println!(
-            "Copied file: {:?} -> {:?}",
-            args.path_buf.display(),
-            args.copy_des.display()
-        );
-    } else {
-        if args.recursive {
-            if let Err(err) = copy_recursive(&args.path_buf, &args.copy_des) {
-                return Err(anyhow::anyhow!("Error: copying folder: {}", err));
-            }
-        } else {
+/// For `--stdout`: writes the one file entry `args` selects to `out`, or
+/// the whole content of a single compressed file.
+pub(crate) fn write_entry(args: &DecompressArgs, out: &mut impl Write) -> anyhow::Result<()> {
+    let path = &args.path;
+    let filter = EntryFilter::from_args(args)?;
+
+    match detect_format(path)? {
+        Format::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            let selected: Vec<String> = archive
+                .file_names()
+                .filter(|name| !name.ends_with('/') && filter.matches(name))
+                .map(String::from)
+                .collect();
+            let name = single_entry(selected, path)?;
+            io::copy(&mut archive.by_name(&name)?, out)?;
+        }
+        Format::Tar(codec) => tar_entry_to(path, &filter, || open_decoded(path, codec), out)?,
+        Format::Stream(Codec::None) => {
             return Err(anyhow::anyhow!(
-                "Error: '{:?}' is a directory. Use '-r' to copy recursively.",
-                args.path_buf
-            ));
+                "Error: {} is not an archive or a compressed file.",
+                path.display()
+            ))
+        }
+        Format::Stream(codec) => {
+            io::copy(&mut open_decoded(path, codec)?, out)?;
         }
     }
     Ok(())
 }
-// #[warn(dead_code)]
-// pub fn move_file() {}
-
-pub fn read_csv_table(args: ReadTableCsvArgs) -> anyhow::Result<()> {
-    let csv_file = CsvFile {
-        file_path: args.path,
-        has_headers: true,
-        skip: if args.start { args.row_num } else { 0 },
-        limit: if args.limit { args.limit_row } else { 1000 },
-    };
-
-    println!("{:?}", args.limit);
 
-    match csv_file.read_file() {
-        Ok(_) => {
-            println!("File successfully read.");
+/// Writes the one regular file selected by `filter` to `out`. `open` is
+/// called twice: once to check the selection is unique, once to stream it.
+fn tar_entry_to<R: Read>(
+    archive_path: &Path,
+    filter: &EntryFilter,
+    open: impl Fn() -> anyhow::Result<R>,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut selected = Vec::new();
+    for entry in tar::Archive::new(open()?).entries()? {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if entry.header().entry_type().is_file() && filter.matches(&name) {
+            selected.push(name);
         }
-        Err(e) => {
-            if let Some(io_err) = e.downcast_ref::<io::Error>() {
-                match io_err.kind() {
-                    ErrorKind::NotFound => {
-                        eprintln!("Error: File not found.");
-                    }
-                    ErrorKind::PermissionDenied => {
-                        eprintln!("Error: Permission denied.");
-                    }
-                    other_kind => {
-                        eprintln!("Error: Some other I/O error: {:?}", other_kind);
-                    }
-                }
-            } else {
-                eprintln!("Error: Some other error: {}", e);
-            }
+    }
+    let name = single_entry(selected, archive_path)?;
+
+    for entry in tar::Archive::new(open()?).entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_string_lossy() == name {
+            io::copy(&mut entry, out)?;
+            break;
         }
     }
     Ok(())
 }
 
-pub fn decompress_zip(args: &DecompressArgs) -> anyhow::Result<()> {
-    let path = &args.path;
-
-    if !path.exists() {
-        return Err(anyhow::anyhow!("Error: File {:?} does not exists.", path));
+fn validate_tar_entries<R: Read>(
+    reader: R,
+    filter: &EntryFilter,
+    strip_unsafe: bool,
+) -> anyhow::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?;
+        if filter.matches(&path.to_string_lossy()) {
+            sanitize_entry_path(&path, strip_unsafe)?;
+        }
     }
+    Ok(())
+}
 
-    let file = File::open(path)?;
+fn unpack_tar<R: Read>(
+    reader: R,
+    output_dir: &Path,
+    filter: &EntryFilter,
+    args: &DecompressArgs,
+    journal: &Journal,
+) -> anyhow::Result<()> {
+    let policy = OverwritePolicy::from_args(args);
+    let op = journal.start();
+    op.create_dir_all(output_dir)?;
 
-    let mut archive = ZipArchive::new(file)?;
+    let mut archive = tar::Archive::new(reader);
+    let mut dir_attrs = Vec::new();
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !filter.matches(&entry.path()?.to_string_lossy()) {
+            continue;
+        }
+        let entry_path = sanitize_entry_path(&entry.path()?, args.strip_unsafe)?;
+        if entry_path.as_os_str().is_empty() {
+            continue;
+        }
 
-        let outpath = Path::new(".").join(file.name());
-        println!("outpath: {:?}", outpath);
-        if file.name().ends_with('/') {
-            fs::create_dir_all(&outpath)?;
-        } else {
-            if let Some(parent) = outpath.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent)?;
+        let outpath = output_dir.join(entry_path);
+        let header = entry.header();
+        let mtime = header
+            .mtime()
+            .ok()
+            .map(|secs| UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        let mode = header.mode().ok();
+
+        match header.entry_type() {
+            tar::EntryType::Directory => {
+                op.create_dir_all(&outpath)?;
+                dir_attrs.push((outpath, mtime, mode));
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                if let Some(parent) = outpath.parent() {
+                    op.create_dir_all(parent)?;
                 }
+
+                let outpath = match resolve_output_path(outpath, policy)? {
+                    Some(outpath) => outpath,
+                    None => continue,
+                };
+
+                op.write(&outpath, || {
+                    write_atomic_no_follow(&outpath, |outfile| {
+                        io::copy(&mut entry, outfile)?;
+                        if let Some(mtime) = mtime {
+                            outfile.set_modified(mtime)?;
+                        }
+                        Ok(())
+                    })
+                })?;
+                set_unix_mode(&outpath, mode)?;
+                println!("outpath: {:?}", outpath);
             }
+            tar::EntryType::XGlobalHeader => {}
+            // Links could point anywhere outside the output dir, so they are not recreated.
+            other => eprintln!("Skipped {:?} entry: {}", other, outpath.display()),
+        }
+    }
 
-            let mut outfile = File::create(&outpath)?;
-            io::copy(&mut file, &mut outfile)?;
+    for (dir, mtime, mode) in dir_attrs.into_iter().rev() {
+        if let Some(mtime) = mtime {
+            set_dir_modified(&dir, mtime)?;
         }
+        set_unix_mode(&dir, mode)?;
     }
-    println!("Decompress {} successfully", path.display());
+
     Ok(())
 }
 
-pub fn decompress_gz(args: &DecompressArgs) -> anyhow::Result<()> {
-    let path = &args.path;
+/// Writes the archive or compressed file asked for by the output's extension.
+pub fn compress(args: &CompressArgs) -> anyhow::Result<()> {
+    let format = format_from_name(&args.output_compress).ok_or_else(|| {
+        anyhow!(
+            "Error: Unknown output format for {}. Use .zip, .tar, .tar.gz, .tar.bz2, .tar.xz, .tar.zst, .gz, .bz2, .xz or .zst.",
+            args.output_compress
+        )
+    })?;
 
-    if !path.exists() {
-        return Err(anyhow::anyhow!("Error: File {:?} does not exists.", path));
+    if args.method.is_some() && format != Format::Zip {
+        return Err(anyhow!("Error: --method only applies to .zip output."));
     }
 
-    let file = File::open(path)?;
+    match format {
+        Format::Zip => compress_to_zip(args),
+        Format::Tar(codec) => compress_to_tar(args, codec),
+        Format::Stream(codec) => compress_stream(args, codec),
+    }
+}
+
+pub fn compress_to_zip(args: &CompressArgs) -> anyhow::Result<()> {
+    let options = zip_options(args.method.unwrap_or(ZipMethod::Deflate), args.level)?;
+    let sources = collect_sources(&args.files, &args.source_filter)?;
 
-    let mut decoder = GzDecoder::new(file);
-    let parent_path = path
-        .parent()
-        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Path has no parent directory"))?;
+    write_atomic(Path::new(&args.output_compress), |zip_file| {
+        let mut zip_writer = ZipWriter::new(zip_file);
 
-    let mut out_file = File::create(parent_path)?;
+        for source in &sources {
+            if source.is_dir {
+                let options = with_file_metadata(options, &fs::metadata(&source.path)?);
+                zip_writer.add_directory(source.name.as_str(), options)?;
+            } else {
+                add_file_to_zip(&mut zip_writer, &source.path, &source.name, &options)?;
+            }
+        }
 
-    io::copy(&mut decoder, &mut out_file)?;
+        zip_writer.finish()?;
+        Ok(())
+    })?;
+    println!("Compressed successfully to {}", args.output_compress);
     Ok(())
 }
 
-pub fn compress_to_zip(args: &CompressArgs) -> anyhow::Result<()> {
-    let paths = &args.files;
-    let zip_file = File::create(&args.output_compress)?;
-    let mut zip_writer = ZipWriter::new(zip_file);
+/// A file or directory going into an archive, with its `/`-separated name there.
+#[derive(Debug)]
+pub(crate) struct SourceEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) name: String,
+    pub(crate) is_dir: bool,
+}
 
-    let options = FileOptions::default()
-        .compression_method(CompressionMethod::Deflated)
-        .unix_permissions(0o644);
+/// Expands the paths given to a compress command. Directories are walked
+/// recursively and keep their own name as the top entry (unless they have
+/// none, like "."); `--exclude` and `--respect-gitignore` prune the walk.
+/// Files named explicitly are always included.
+pub(crate) fn collect_sources(
+    paths: &[String],
+    filter: &SourceFilterArgs,
+) -> anyhow::Result<Vec<SourceEntry>> {
+    let mut sources = Vec::new();
 
     for path in paths {
         let path_buf = PathBuf::from(path);
 
-        if path_buf.is_dir() {
-            for entry in WalkDir::new(&path_buf) {
-                let entry = entry.map_err(|e| anyhow!("Error reading directory: {}", e))?;
-                let entry_path = entry.path();
-
-                if entry_path.is_dir() {
-                    continue;
-                }
-
-                let relative_path = path_buf
-                    .parent()
-                    .map(|parent| entry_path.strip_prefix(parent));
-
-                if let Some(relative_path) = relative_path {
-                    let relative_path_str = relative_path?
-                        .to_str()
-                        .ok_or_else(|| anyhow!("Invalid UTF-8 path"))?;
-                    add_file_to_zip(&mut zip_writer, entry_path, relative_path_str, &options)?;
-                }
-            }
-        } else {
+        if !path_buf.is_dir() {
             let file_name = path_buf
                 .file_name()
                 .ok_or_else(|| anyhow!("Invalid file name"))?
                 .to_str()
                 .ok_or_else(|| anyhow!("Invalid UTF-8 file name"))?;
+            sources.push(SourceEntry {
+                path: path_buf.clone(),
+                name: file_name.to_string(),
+                is_dir: false,
+            });
+            continue;
+        }
+
+        let base = match (path_buf.file_name(), path_buf.parent()) {
+            (Some(_), Some(parent)) => parent.to_path_buf(),
+            _ => path_buf.clone(),
+        };
 
-            add_file_to_zip(&mut zip_writer, &path_buf, file_name, &options)?;
+        let mut overrides = OverrideBuilder::new(&path_buf);
+        for pattern in &filter.exclude {
+            overrides
+                .add(&format!("!{}", pattern))
+                .with_context(|| format!("Invalid exclude pattern: {}", pattern))?;
+        }
+
+        let mut builder = WalkBuilder::new(&path_buf);
+        builder
+            .standard_filters(false)
+            .git_ignore(filter.respect_gitignore)
+            .git_exclude(filter.respect_gitignore)
+            .git_global(filter.respect_gitignore)
+            .ignore(filter.respect_gitignore)
+            .parents(filter.respect_gitignore)
+            .require_git(false)
+            .overrides(overrides.build()?)
+            .sort_by_file_name(|a, b| a.cmp(b));
+        if filter.respect_gitignore {
+            builder.filter_entry(|entry| entry.file_name() != ".git");
+        }
+
+        for entry in builder.build() {
+            let entry = entry.map_err(|e| anyhow!("Error reading directory: {}", e))?;
+            let name = zip_entry_name(entry.path().strip_prefix(&base)?)?;
+            if name.is_empty() {
+                continue;
+            }
+
+            sources.push(SourceEntry {
+                path: entry.path().to_path_buf(),
+                name,
+                is_dir: entry.file_type().is_some_and(|t| t.is_dir()),
+            });
         }
     }
 
-    zip_writer.finish()?;
-    println!("Compressed successfully to {}", args.output_compress);
-    Ok(())
+    Ok(sources)
+}
+
+fn zip_options(method: ZipMethod, level: Option<i32>) -> anyhow::Result<FileOptions> {
+    let (compression_method, levels) = match method {
+        ZipMethod::Stored => (CompressionMethod::Stored, None),
+        ZipMethod::Deflate => (CompressionMethod::Deflated, Some(0..=9)),
+        ZipMethod::Bzip2 => (CompressionMethod::Bzip2, Some(1..=9)),
+        ZipMethod::Zstd => (CompressionMethod::Zstd, Some(1..=22)),
+    };
+
+    if let Some(level) = level {
+        match levels {
+            Some(range) if range.contains(&level) => {}
+            Some(range) => {
+                return Err(anyhow!(
+                    "Error: Level {} is out of range {}-{} for {:?}.",
+                    level,
+                    range.start(),
+                    range.end(),
+                    method
+                ))
+            }
+            None => return Err(anyhow!("Error: {:?} does not take a level.", method)),
+        }
+    }
+
+    Ok(FileOptions::default()
+        .compression_method(compression_method)
+        .compression_level(level))
+}
+
+/// Zip entry names always use `/`, whatever the platform separator is.
+fn zip_entry_name(relative_path: &Path) -> anyhow::Result<String> {
+    let mut parts = Vec::new();
+    for component in relative_path.components() {
+        parts.push(
+            component
+                .as_os_str()
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid UTF-8 path"))?,
+        );
+    }
+    Ok(parts.join("/"))
+}
+
+/// Carries the source's permissions and modification time into the entry.
+fn with_file_metadata(options: FileOptions, metadata: &Metadata) -> FileOptions {
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    };
+    #[cfg(not(unix))]
+    let mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+
+    let options = options
+        .unix_permissions(mode)
+        .large_file(metadata.len() >= u32::MAX as u64);
+
+    match metadata.modified().ok().and_then(system_time_to_zip_time) {
+        Some(mtime) => options.last_modified_time(mtime),
+        None => options,
+    }
+}
+
+fn system_time_to_zip_time(time: SystemTime) -> Option<zip::DateTime> {
+    use chrono::{Datelike, Local, Timelike};
+
+    let local = chrono::DateTime::<Local>::from(time);
+    zip::DateTime::from_date_and_time(
+        u16::try_from(local.year()).ok()?,
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    )
+    .ok()
 }
 
 fn add_file_to_zip<W: Write + io::Seek>(
@@ -644,29 +2819,57 @@ fn add_file_to_zip<W: Write + io::Seek>(
         other_kind => anyhow!("Error: Some other I/O error: {:?}", other_kind),
     })?;
 
-    let mut buffer = Vec::new();
-    f.read_to_end(&mut buffer)
+    let options = with_file_metadata(*options, &f.metadata()?);
+    zip_writer.start_file(zip_entry_name, options)?;
+    io::copy(&mut f, zip_writer)
         .map_err(|e| anyhow!("Error reading file {}: {}", file_path.display(), e))?;
 
-    zip_writer.start_file(zip_entry_name, *options)?;
-    zip_writer.write_all(&buffer)?;
-
     Ok(())
 }
 
-pub fn compress_to_tar_gz(args: &CompressArgsGz) -> anyhow::Result<()> {
-    let paths = &args.files;
+pub fn compress_to_tar(args: &CompressArgs, codec: Codec) -> anyhow::Result<()> {
+    let sources = collect_sources(&args.files, &args.source_filter)?;
 
-    let tar_gz = File::create(&args.output_compress)?;
+    write_atomic(Path::new(&args.output_compress), |tar_file| {
+        let enc = codec.encoder(tar_file, args.level)?;
+        let mut tar_builder = Builder::new(enc);
 
-    let enc = GzEncoder::new(tar_gz, Compression::default());
-    let mut tar_builder = Builder::new(enc);
+        for source in &sources {
+            if source.is_dir {
+                tar_builder.append_dir(&source.name, &source.path)?;
+            } else {
+                tar_builder.append_path_with_name(&source.path, &source.name)?;
+            }
+        }
 
-    for path in paths.iter() {
-        tar_builder.append_path(path)?;
-    }
+        tar_builder.into_inner()?.finish()?;
+        Ok(())
+    })?;
+    println!("Compressed successfully to {}", args.output_compress);
+    Ok(())
+}
+
+/// `.gz`, `.bz2`, `.xz` and `.zst` hold exactly one file, without a name or metadata.
+fn compress_stream(args: &CompressArgs, codec: Codec) -> anyhow::Result<()> {
+    let source = match args.files.as_slice() {
+        [file] if !Path::new(file).is_dir() => Path::new(file),
+        _ => {
+            return Err(anyhow!(
+                "Error: {} holds a single file. Use a .tar.* or .zip output for several files or directories.",
+                args.output_compress
+            ))
+        }
+    };
+
+    let mut input =
+        File::open(source).with_context(|| format!("Failed to open {}", source.display()))?;
+    write_atomic(Path::new(&args.output_compress), |output| {
+        let mut enc = codec.encoder(output, args.level)?;
+        io::copy(&mut input, &mut enc)?;
+        enc.finish()?;
+        Ok(())
+    })?;
 
-    tar_builder.finish()?;
     println!("Compressed successfully to {}", args.output_compress);
     Ok(())
 }
@@ -681,9 +2884,10 @@ pub fn csv_to_json(args: &CsvToJsonArgs) -> anyhow::Result<()> {
         ));
     }
 
-    if !csv_path.is_file() || !is_csv(csv_path) {
+    if !csv_path.is_file() {
         return Err(anyhow::anyhow!("{:?} is not a csv file", csv_path));
     }
+    check_data_file(csv_path, "CSV", is_csv_kind, is_csv(csv_path))?;
 
     let csv_file = CsvFile {
         file_path: csv_path.clone(),
@@ -705,9 +2909,15 @@ pub fn json_to_csv(args: &JsonToCsvArgs) -> anyhow::Result<()> {
         ));
     }
 
-    if !json_path.is_file() || !is_json_file(json_path) {
+    if !json_path.is_file() {
         return Err(anyhow::anyhow!("{:?} is not a json file", json_path));
     }
+    check_data_file(
+        json_path,
+        "JSON",
+        |kind| kind == FileKind::Json,
+        is_json_file(json_path),
+    )?;
 
     let json_file = JsonFile {
         file_path: json_path.clone(),
@@ -715,18 +2925,48 @@ pub fn json_to_csv(args: &JsonToCsvArgs) -> anyhow::Result<()> {
     json_file.parse_to_csv(&args.output_csv_path)?;
     Ok(())
 }
-fn is_json_file(path: &PathBuf) -> bool {
-    match path.extension() {
-        Some(ext) if ext == "json" => true,
-        _ => false,
-    }
+fn is_json_file(path: &Path) -> bool {
+    matches!(path.extension(), Some(ext) if ext == "json")
 }
 
-fn is_csv(path: &PathBuf) -> bool {
-    match path.extension() {
-        Some(ext) if ext == "csv" => true,
-        _ => false,
+fn is_csv(path: &Path) -> bool {
+    matches!(path.extension(), Some(ext) if ext == "csv")
+}
+
+fn is_csv_kind(kind: FileKind) -> bool {
+    matches!(kind, FileKind::Csv(_) | FileKind::Tsv)
+}
+
+/// Checks by content that `path` holds what a command expects. A file with
+/// the right extension that only looks like plain text is let through, so
+/// the parser can point at what is wrong with it.
+fn check_data_file(
+    path: &Path,
+    wanted: &str,
+    accepts: fn(FileKind) -> bool,
+    has_extension: bool,
+) -> anyhow::Result<()> {
+    let identity = identify(path)?;
+    if accepts(identity.kind)
+        || (has_extension && matches!(identity.kind, FileKind::Text | FileKind::Empty))
+    {
+        return Ok(());
     }
+
+    let hint = match identity.kind {
+        FileKind::Zip | FileKind::Tar | FileKind::Compressed(_) => {
+            " Extract it first with `decompress`."
+        }
+        FileKind::Utf16Text => " Convert it to UTF-8 first.",
+        _ => "",
+    };
+    Err(anyhow!(
+        "Error: {} is {}, not {}.{}",
+        path.display(),
+        identity,
+        wanted,
+        hint
+    ))
 }
 
 pub fn read_file(args: &CatFileArgs) -> anyhow::Result<()> {
@@ -791,37 +3031,74 @@ pub fn format_size(size: u64, human_readable: bool) -> String {
     }
 }
 
-fn remove_all_files_in_dir(path: &Path) -> anyhow::Result<()> {
-    if path.is_dir() {
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let entry_path = entry.path();
+/// Parses line ranges such as `10:200`, `10:`, `:200` or `42`, counted from 1.
+pub fn parse_line_range(input: &str) -> anyhow::Result<LineRange> {
+    let number = |value: &str| -> anyhow::Result<Option<usize>> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let line: usize = value
+            .parse()
+            .with_context(|| format!("Invalid line range '{}'. Example: 10:200", input))?;
+        if line == 0 {
+            return Err(anyhow!(
+                "Invalid line range '{}': lines count from 1.",
+                input
+            ));
+        }
+        Ok(Some(line))
+    };
 
-            if entry_path.is_file() {
-                fs::remove_file(&entry_path)?;
-                println!("Removed file: {:?}", entry_path);
-            } else if entry_path.is_dir() {
-                remove_all_files_in_dir(&entry_path)?; // Recursively remove files in subdirectories
+    let range = match input.split_once(':') {
+        Some((start, end)) => LineRange {
+            start: number(start)?.unwrap_or(1),
+            end: number(end)?,
+        },
+        None => {
+            let line = number(input)?
+                .ok_or_else(|| anyhow!("Invalid line range '{}'. Example: 10:200", input))?;
+            LineRange {
+                start: line,
+                end: Some(line),
             }
         }
+    };
+
+    if range.end.is_some_and(|end| end < range.start) {
+        return Err(anyhow!(
+            "Invalid line range '{}': the end is before the start.",
+            input
+        ));
     }
-    Ok(())
+    Ok(range)
 }
-fn remove_all_dir_in_dir(path: &Path) -> io::Result<()> {
-    if path.is_dir() {
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let entry_path = entry.path();
-            if entry_path.is_dir() {
-                fs::remove_dir(&entry_path)?;
-                println!("Removed file: {:?}", entry_path);
-            }
-        }
-    }
-    Ok(())
+
+/// Parses sizes such as `512`, `64K`, `16M` or `2G` (powers of 1024).
+pub fn parse_size(input: &str) -> anyhow::Result<u64> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("Invalid size '{}'. Example: 64M", input))?;
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(anyhow!("Invalid size unit '{}'. Use K, M or G.", unit)),
+    };
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Size '{}' is too large.", input))
 }
 
-fn copy_recursive(src: &PathBuf, dest: &PathBuf) -> anyhow::Result<()> {
+fn copy_recursive(src: &PathBuf, dest: &PathBuf, op: &Operation) -> anyhow::Result<()> {
     if !src.exists() {
         return Err(anyhow::anyhow!(
             "Source folder {:?} does not exist.",
@@ -829,22 +3106,34 @@ fn copy_recursive(src: &PathBuf, dest: &PathBuf) -> anyhow::Result<()> {
         ));
     }
 
+    copy_dir_all(src, dest, Some(op))?;
+    println!("Copied folder: {:?} -> {:?}", src, dest);
+    Ok(())
+}
+
+/// Copies the tree `src` to `dest`, recording what it writes in `op` if given.
+fn copy_dir_all(src: &Path, dest: &Path, op: Option<&Operation>) -> anyhow::Result<()> {
     for entry in WalkDir::new(src) {
         let entry = entry?;
         let relative_path = entry.path().strip_prefix(src)?;
 
         let dest_path = dest.join(relative_path);
 
-        if entry.path().is_dir() {
-            fs::create_dir_all(&dest_path)?;
-        } else {
-            fs::copy(entry.path(), &dest_path)?;
+        match (entry.path().is_dir(), op) {
+            (true, Some(op)) => op.create_dir_all(&dest_path)?,
+            (true, None) => fs::create_dir_all(&dest_path)?,
+            (false, Some(op)) => {
+                op.write(&dest_path, || Ok(fs::copy(entry.path(), &dest_path)?))?;
+            }
+            (false, None) => {
+                fs::copy(entry.path(), &dest_path)?;
+            }
         }
     }
-    println!("Copied folder: {:?} -> {:?}", src, dest);
     Ok(())
 }
 
+#[allow(dead_code)]
 pub struct TxtFile {
     pub file_path: String,
 }
@@ -861,33 +3150,35 @@ pub struct JsonFile {
 }
 
 impl JsonFile {
-    pub fn parse_to_csv(&self, output_file_name: &PathBuf) -> anyhow::Result<()> {
+    pub fn parse_to_csv(&self, output_file_name: &Path) -> anyhow::Result<()> {
 
         let json_file = File::open(&self.file_path)?;
 
         let json_data: Value = serde_json::from_reader(json_file)?;
 
-        let mut csv_writer = Writer::from_path(output_file_name)?;
-
-        match json_data {
-            Value::Array(array) => {
-                for (i, item) in array.iter().enumerate() {
-                    if let Value::Object(obj) = item {
-                        if i == 0 {
-                            csv_writer.write_record(obj.keys())?;
-                        }
-                        csv_writer.write_record(obj.values().map(|v| v.to_string()))?;
-                    }
-                }
-            }
+        let array = match json_data {
+            Value::Array(array) => array,
             _ => {
                 return Err(anyhow::anyhow!(
                     "Invalid JSON format for CSV: Expected an array of objects"
                 ))
             }
-        }
+        };
+
+        write_atomic(output_file_name, |file| {
+            let mut csv_writer = Writer::from_writer(file);
+            for (i, item) in array.iter().enumerate() {
+                if let Value::Object(obj) = item {
+                    if i == 0 {
+                        csv_writer.write_record(obj.keys())?;
+                    }
+                    csv_writer.write_record(obj.values().map(|v| v.to_string()))?;
+                }
+            }
 
-        csv_writer.flush().context("Failed to flush CSV writer")?;
+            csv_writer.flush().context("Failed to flush CSV writer")?;
+            Ok(())
+        })?;
 
         Ok(())
     }
@@ -953,9 +3244,10 @@ impl CsvFile {
             &*format!("{}.json", output_file_name)
         };
 
-        let mut output_file = File::create(json_output_path)?;
-
-        output_file.write_all(json_output.to_string().as_bytes())?;
+        write_atomic(Path::new(json_output_path), |output_file| {
+            output_file.write_all(json_output.to_string().as_bytes())?;
+            Ok(())
+        })?;
 
         println!(
             "CSV has been successfully converted to JSON. Output saved to {}.",
@@ -976,7 +3268,7 @@ impl FileReader for CsvFile {
 
         println!("CSV File Contents:");
 
-        if let Some(headers) = reader.headers().ok() {
+        if let Ok(headers) = reader.headers() {
             for header in headers.iter() {
                 print!("{:<15}", header);
             }
@@ -0,0 +1,680 @@
+use crate::command::{HistoryArgs, UndoArgs};
+use crate::file_handler::{move_by_copy, write_atomic};
+use crate::rename::{conflicts, rename_all};
+use crate::trash::{check_id, data_dir, parse_age, Trash};
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Duration, Local};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+const JOURNAL_DIR_ENV: &str = "CLAP_TOOL_FILE_JOURNAL";
+
+/// Record of the operations that changed files. Operation `<id>` lives in
+/// `<id>/`: `op.json` says what ran and when, `changes.jsonl` lists what it
+/// did, one change per line, and `stash/` keeps the contents it replaced.
+/// Files its operations trash go to `trash`.
+pub struct Journal {
+    root: PathBuf,
+    trash: Trash,
+}
+
+/// One change made by an operation, with what is needed to take it back.
+/// Paths are absolute.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// `path` existed and was overwritten; its old contents are `stash`.
+    Modified {
+        path: PathBuf,
+        stash: String,
+        after: Stamp,
+    },
+    /// `path` was written where there was nothing.
+    Created { path: PathBuf, after: Stamp },
+    /// The directory `path` was created.
+    CreatedDir { path: PathBuf },
+    /// `from` was moved to `to`, replacing the file stashed as `replaced`.
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+        replaced: Option<String>,
+    },
+    /// Each `from` was moved to its `to` in one batch, so a name may have
+    /// passed from one file to another.
+    RenamedAll { moves: Vec<(PathBuf, PathBuf)> },
+    /// `path` went to the trash as `trash_id`.
+    Trashed { path: PathBuf, trash_id: String },
+}
+
+/// Size and modification time of a file just after it was written, to tell
+/// whether it changed since.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stamp {
+    pub len: u64,
+    pub modified: String,
+}
+
+#[derive(Debug)]
+pub struct OperationRecord {
+    pub id: String,
+    pub command: String,
+    pub started_at: DateTime<Local>,
+    pub undone_at: Option<DateTime<Local>>,
+    pub changes: Vec<Change>,
+}
+
+/// An operation being recorded. Nothing is written to the journal until the
+/// first change, so operations that change nothing leave no trace.
+pub struct Operation {
+    root: PathBuf,
+    command: String,
+    started_at: DateTime<Local>,
+    active: Mutex<Option<(PathBuf, File)>>,
+    stashed: AtomicUsize,
+    /// Paths written so far, with the stash of what was there before the first write.
+    written: Mutex<HashMap<PathBuf, Option<String>>>,
+}
+
+impl Stamp {
+    pub fn of(path: &Path) -> anyhow::Result<Stamp> {
+        let md = fs::metadata(path)?;
+        let modified = md.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+        Ok(Stamp {
+            len: md.len(),
+            modified: modified.to_string(),
+        })
+    }
+}
+
+impl Journal {
+    pub fn new(root: PathBuf, trash: Trash) -> Self {
+        Journal { root, trash }
+    }
+
+    /// Opens the journal of the current user, `$CLAP_TOOL_FILE_JOURNAL` if
+    /// set, otherwise `journal` in the data dir of the trash, along with the
+    /// user's trash.
+    pub fn open_default() -> anyhow::Result<Self> {
+        let trash = Trash::open_default()?;
+        if let Some(dir) = std::env::var_os(JOURNAL_DIR_ENV) {
+            return Ok(Journal::new(PathBuf::from(dir), trash));
+        }
+        Ok(Journal::new(data_dir()?.join("journal"), trash))
+    }
+
+    pub fn trash(&self) -> &Trash {
+        &self.trash
+    }
+
+    /// Starts recording an operation described by the command line.
+    pub fn start(&self) -> Operation {
+        let command: Vec<String> = std::env::args().skip(1).collect();
+        self.begin(&command.join(" "))
+    }
+
+    /// Starts recording an operation described by `command`.
+    pub fn begin(&self, command: &str) -> Operation {
+        Operation {
+            root: self.root.clone(),
+            command: command.to_string(),
+            started_at: Local::now(),
+            active: Mutex::new(None),
+            stashed: AtomicUsize::new(0),
+            written: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns every recorded operation, oldest first.
+    pub fn list(&self) -> anyhow::Result<Vec<OperationRecord>> {
+        let mut records = Vec::new();
+
+        let dir = match fs::read_dir(&self.root) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(records),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in dir {
+            let path = entry?.path();
+            if path.join("op.json").exists() {
+                records.push(read_record(&path)?);
+            }
+        }
+
+        records.sort_by(|a, b| a.started_at.cmp(&b.started_at).then(a.id.cmp(&b.id)));
+        Ok(records)
+    }
+
+    /// Takes back every change of operation `id`, newest first. Nothing is
+    /// touched when a file changed since or is in the way; those are all
+    /// reported together.
+    pub fn undo(&self, id: &str) -> anyhow::Result<()> {
+        check_id(id)?;
+        let dir = self.root.join(id);
+        if !dir.join("op.json").exists() {
+            return Err(anyhow!("Error: No operation with id '{}'.", id));
+        }
+
+        let record = read_record(&dir)?;
+        if record.undone_at.is_some() {
+            return Err(anyhow!("Error: Operation '{}' was already undone.", id));
+        }
+
+        let trash = &self.trash;
+        let conflicts: Vec<String> = record
+            .changes
+            .iter()
+            .filter_map(|change| conflict(change, trash))
+            .collect();
+        if !conflicts.is_empty() {
+            return Err(anyhow!(
+                "Error: Cannot undo '{}':\n  {}",
+                id,
+                conflicts.join("\n  ")
+            ));
+        }
+
+        let stash = |name: &str| dir.join("stash").join(name);
+        let restore = |path: &Path, name: &str| {
+            let mut stashed = File::open(stash(name))?;
+            let modified = stashed.metadata()?.modified()?;
+            write_atomic(path, |file| {
+                std::io::copy(&mut stashed, file)?;
+                // Back to how it was, so earlier operations see their own file again.
+                file.set_modified(modified)?;
+                Ok(())
+            })
+        };
+
+        for change in record.changes.iter().rev() {
+            match change {
+                Change::Modified { path, stash, .. } => restore(path, stash)?,
+                Change::Created { path, .. } => fs::remove_file(path)?,
+                // Whatever was put in it since stays, and so does the directory.
+                Change::CreatedDir { path } => {
+                    let _ = fs::remove_dir(path);
+                }
+                Change::Renamed { from, to, replaced } => {
+                    if let Some(parent) = from.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    match fs::rename(to, from) {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == ErrorKind::CrossesDevices => move_by_copy(to, from)?,
+                        Err(e) => return Err(e.into()),
+                    }
+                    if let Some(replaced) = replaced {
+                        restore(to, replaced)?;
+                    }
+                }
+                Change::RenamedAll { moves } => rename_all(&reversed(moves))?,
+                Change::Trashed { trash_id, .. } => {
+                    trash.restore(trash_id)?;
+                }
+            }
+        }
+
+        let mut info = read_json(&dir.join("op.json"))?;
+        info["undone_at"] = json!(Local::now().to_rfc3339());
+        fs::write(dir.join("op.json"), serde_json::to_string_pretty(&info)?)?;
+        let _ = fs::remove_dir_all(dir.join("stash"));
+
+        Ok(())
+    }
+
+    /// Forgets recorded operations, only those older than `older_than` when
+    /// given, along with what they stashed. Returns how many were forgotten.
+    pub fn prune(&self, older_than: Option<Duration>) -> anyhow::Result<usize> {
+        let now = Local::now();
+        let mut removed = 0;
+
+        for record in self.list()? {
+            if let Some(age) = older_than {
+                if now - record.started_at < age {
+                    continue;
+                }
+            }
+
+            check_id(&record.id)?;
+            fs::remove_dir_all(self.root.join(&record.id))?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+}
+
+/// What stops `change` from being taken back, if anything.
+fn conflict(change: &Change, trash: &Trash) -> Option<String> {
+    let changed_since = |path: &Path, after: &Stamp| match Stamp::of(path) {
+        Ok(stamp) if stamp == *after => None,
+        Ok(_) => Some(format!("'{}' changed since.", path.display())),
+        Err(_) => Some(format!("'{}' is gone.", path.display())),
+    };
+
+    match change {
+        Change::Modified { path, after, .. } | Change::Created { path, after } => {
+            changed_since(path, after)
+        }
+        Change::Renamed { from, to, .. } => {
+            if fs::symlink_metadata(to).is_err() {
+                Some(format!("'{}' is gone.", to.display()))
+            } else if fs::symlink_metadata(from).is_ok() {
+                Some(format!("'{}' exists again.", from.display()))
+            } else {
+                None
+            }
+        }
+        Change::RenamedAll { moves } => {
+            let conflicts = conflicts(&reversed(moves));
+            (!conflicts.is_empty()).then(|| conflicts.join("\n  "))
+        }
+        Change::Trashed { path, trash_id } => {
+            if !trash.contains(trash_id) {
+                Some(format!("'{}' is no longer in the trash.", path.display()))
+            } else if fs::symlink_metadata(path).is_ok() {
+                Some(format!("'{}' exists again.", path.display()))
+            } else {
+                None
+            }
+        }
+        Change::CreatedDir { .. } => None,
+    }
+}
+
+impl Operation {
+    /// The operation's directory, created with `op.json` on first use.
+    fn dir(&self) -> anyhow::Result<PathBuf> {
+        let mut active = self.active.lock().unwrap();
+        if let Some((dir, _)) = active.as_ref() {
+            return Ok(dir.clone());
+        }
+
+        fs::create_dir_all(&self.root)?;
+        let base = self.started_at.format("%Y%m%d-%H%M%S").to_string();
+        let mut id = base.clone();
+        let mut counter = 1;
+        // Creating the directory claims the id, even against other processes.
+        let dir = loop {
+            let dir = self.root.join(&id);
+            match fs::create_dir(&dir) {
+                Ok(_) => break dir,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    id = format!("{}-{}", base, counter);
+                    counter += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        fs::create_dir(dir.join("stash"))?;
+        let info = json!({
+            "id": id,
+            "command": self.command,
+            "started_at": self.started_at.to_rfc3339(),
+        });
+        fs::write(dir.join("op.json"), serde_json::to_string_pretty(&info)?)?;
+        let changes = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join("changes.jsonl"))?;
+
+        *active = Some((dir.clone(), changes));
+        Ok(dir)
+    }
+
+    fn record(&self, change: Change) -> anyhow::Result<()> {
+        self.dir()?;
+        let mut active = self.active.lock().unwrap();
+        if let Some((_, changes)) = active.as_mut() {
+            writeln!(changes, "{}", change_to_json(&change))?;
+            changes.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Copies `path` into the stash when it is an existing file.
+    fn stash(&self, path: &Path) -> anyhow::Result<Option<String>> {
+        if !fs::symlink_metadata(path).is_ok_and(|md| md.is_file()) {
+            return Ok(None);
+        }
+
+        let name = self.stashed.fetch_add(1, Ordering::Relaxed).to_string();
+        let stash = self.dir()?.join("stash").join(&name);
+        fs::copy(path, &stash)
+            .with_context(|| format!("Failed to save {} to the journal", path.display()))?;
+        File::options()
+            .write(true)
+            .open(&stash)?
+            .set_modified(fs::metadata(path)?.modified()?)?;
+        Ok(Some(name))
+    }
+
+    /// Runs `write`, which writes the file `path`, keeping what was there so
+    /// it can be put back. Writing a path again keeps what was there before
+    /// the first write.
+    pub fn write<T>(
+        &self,
+        path: &Path,
+        write: impl FnOnce() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let path = std::path::absolute(path)?;
+        let earlier = self.written.lock().unwrap().get(&path).cloned();
+        let stash = match earlier {
+            Some(stash) => stash,
+            None => self.stash(&path)?,
+        };
+
+        let result = write()?;
+
+        let after = Stamp::of(&path)?;
+        self.written
+            .lock()
+            .unwrap()
+            .insert(path.clone(), stash.clone());
+        self.record(match stash {
+            Some(stash) => Change::Modified { path, stash, after },
+            None => Change::Created { path, after },
+        })?;
+        Ok(result)
+    }
+
+    /// Runs `rename`, which moves `from` to `to`, keeping a file it replaces.
+    pub fn rename<T>(
+        &self,
+        from: &Path,
+        to: &Path,
+        rename: impl FnOnce() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let from = std::path::absolute(from)?;
+        let to = std::path::absolute(to)?;
+        let replaced = self.stash(&to)?;
+
+        let result = rename()?;
+
+        self.record(Change::Renamed { from, to, replaced })?;
+        Ok(result)
+    }
+
+    /// Runs `rename`, which does all of `moves` or none of them.
+    pub fn rename_all<T>(
+        &self,
+        moves: &[(PathBuf, PathBuf)],
+        rename: impl FnOnce() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let moves = moves
+            .iter()
+            .map(|(from, to)| Ok((std::path::absolute(from)?, std::path::absolute(to)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let result = rename()?;
+
+        self.record(Change::RenamedAll { moves })?;
+        Ok(result)
+    }
+
+    /// Creates `path` and its missing parents, recording each one created.
+    pub fn create_dir_all(&self, path: &Path) -> anyhow::Result<()> {
+        // The empty path is the current dir, which is already there.
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let path = std::path::absolute(path)?;
+        let missing: Vec<PathBuf> = path
+            .ancestors()
+            .take_while(|dir| fs::symlink_metadata(dir).is_err())
+            .map(Path::to_path_buf)
+            .collect();
+
+        fs::create_dir_all(&path)?;
+        for dir in missing.into_iter().rev() {
+            self.record(Change::CreatedDir { path: dir })?;
+        }
+        Ok(())
+    }
+
+    pub fn trashed(&self, path: &Path, trash_id: &str) -> anyhow::Result<()> {
+        self.record(Change::Trashed {
+            path: std::path::absolute(path)?,
+            trash_id: trash_id.to_string(),
+        })
+    }
+}
+
+pub fn run_undo(args: &UndoArgs, journal: &Journal) -> anyhow::Result<()> {
+    let ids: Vec<String> = match &args.id {
+        Some(id) => vec![id.clone()],
+        None => {
+            let pending: Vec<String> = journal
+                .list()?
+                .into_iter()
+                .filter(|record| record.undone_at.is_none())
+                .map(|record| record.id)
+                .collect();
+            if pending.is_empty() {
+                return Err(anyhow!("Error: Nothing to undo."));
+            }
+            pending.into_iter().rev().take(args.last).collect()
+        }
+    };
+
+    for id in ids {
+        journal.undo(&id)?;
+        println!("Undid operation {}", id);
+    }
+    Ok(())
+}
+
+pub fn run_history(args: &HistoryArgs, journal: &Journal) -> anyhow::Result<()> {
+    if args.prune {
+        let older_than = args.older_than.as_deref().map(parse_age).transpose()?;
+        let removed = journal.prune(older_than)?;
+        println!("Forgot {} operation(s)", removed);
+        return Ok(());
+    }
+
+    let records = journal.list()?;
+    if records.is_empty() {
+        println!("No operations recorded.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<20} {:<8} {:<8} Command",
+        "Id", "Date", "Changes", "Status"
+    );
+    println!("{:-<20} {:-<20} {:-<8} {:-<8} {:-<7}", "", "", "", "", "");
+    for record in records {
+        println!(
+            "{:<20} {:<20} {:<8} {:<8} {}",
+            record.id,
+            record.started_at.format("%Y-%m-%d %H:%M:%S"),
+            record.changes.len(),
+            if record.undone_at.is_some() {
+                "undone"
+            } else {
+                "done"
+            },
+            record.command
+        );
+    }
+    Ok(())
+}
+
+fn reversed(moves: &[(PathBuf, PathBuf)]) -> Vec<(PathBuf, PathBuf)> {
+    moves
+        .iter()
+        .rev()
+        .map(|(from, to)| (to.clone(), from.clone()))
+        .collect()
+}
+
+/// Folds writes of a path already written into the first change to it,
+/// which holds what was there before, taking the stamp of the last one.
+fn merge_rewrites(changes: Vec<Change>) -> Vec<Change> {
+    let mut merged: Vec<Change> = Vec::with_capacity(changes.len());
+    let mut written: HashMap<PathBuf, usize> = HashMap::new();
+
+    for change in changes {
+        if let Change::Modified { path, after, .. } | Change::Created { path, after } = &change {
+            if let Some(&first) = written.get(path) {
+                if let Change::Modified { after: last, .. } | Change::Created { after: last, .. } =
+                    &mut merged[first]
+                {
+                    *last = after.clone();
+                }
+                continue;
+            }
+            written.insert(path.clone(), merged.len());
+        }
+        merged.push(change);
+    }
+    merged
+}
+
+fn read_json(path: &Path) -> anyhow::Result<Value> {
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Corrupt journal file {}", path.display()))
+}
+
+fn read_record(dir: &Path) -> anyhow::Result<OperationRecord> {
+    let op_path = dir.join("op.json");
+    let info = read_json(&op_path)?;
+    let field = |name: &str| {
+        info[name]
+            .as_str()
+            .ok_or_else(|| anyhow!("Journal file {} is missing '{}'", op_path.display(), name))
+    };
+    let parse_date = |value: &str| -> anyhow::Result<DateTime<Local>> {
+        Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Local))
+    };
+
+    // A line cut short by a crash is the last one; what came before still counts.
+    let changes = match fs::read_to_string(dir.join("changes.jsonl")) {
+        Ok(contents) => contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .map(|value| change_from_json(&value))
+            .collect::<anyhow::Result<Vec<Change>>>()?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let changes = merge_rewrites(changes);
+
+    Ok(OperationRecord {
+        id: field("id")?.to_string(),
+        command: field("command")?.to_string(),
+        started_at: parse_date(field("started_at")?)?,
+        undone_at: info["undone_at"].as_str().map(parse_date).transpose()?,
+        changes,
+    })
+}
+
+fn change_to_json(change: &Change) -> Value {
+    let stamp = |after: &Stamp| json!({ "len": after.len, "modified": after.modified });
+    match change {
+        Change::Modified { path, stash, after } => json!({
+            "kind": "modified",
+            "path": path.to_string_lossy(),
+            "stash": stash,
+            "after": stamp(after),
+        }),
+        Change::Created { path, after } => json!({
+            "kind": "created",
+            "path": path.to_string_lossy(),
+            "after": stamp(after),
+        }),
+        Change::CreatedDir { path } => json!({
+            "kind": "created_dir",
+            "path": path.to_string_lossy(),
+        }),
+        Change::Renamed { from, to, replaced } => json!({
+            "kind": "renamed",
+            "from": from.to_string_lossy(),
+            "to": to.to_string_lossy(),
+            "replaced": replaced,
+        }),
+        Change::RenamedAll { moves } => json!({
+            "kind": "renamed_all",
+            "moves": moves
+                .iter()
+                .map(|(from, to)| json!({
+                    "from": from.to_string_lossy(),
+                    "to": to.to_string_lossy(),
+                }))
+                .collect::<Vec<Value>>(),
+        }),
+        Change::Trashed { path, trash_id } => json!({
+            "kind": "trashed",
+            "path": path.to_string_lossy(),
+            "trash_id": trash_id,
+        }),
+    }
+}
+
+fn change_from_json(value: &Value) -> anyhow::Result<Change> {
+    let field = |name: &str| {
+        value[name]
+            .as_str()
+            .ok_or_else(|| anyhow!("Journal change is missing '{}'", name))
+    };
+    let path = |name: &str| field(name).map(PathBuf::from);
+    let after = || -> anyhow::Result<Stamp> {
+        Ok(Stamp {
+            len: value["after"]["len"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Journal change is missing 'after.len'"))?,
+            modified: value["after"]["modified"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Journal change is missing 'after.modified'"))?
+                .to_string(),
+        })
+    };
+
+    Ok(match field("kind")? {
+        "modified" => Change::Modified {
+            path: path("path")?,
+            stash: field("stash")?.to_string(),
+            after: after()?,
+        },
+        "created" => Change::Created {
+            path: path("path")?,
+            after: after()?,
+        },
+        "created_dir" => Change::CreatedDir {
+            path: path("path")?,
+        },
+        "renamed" => Change::Renamed {
+            from: path("from")?,
+            to: path("to")?,
+            replaced: value["replaced"].as_str().map(str::to_string),
+        },
+        "renamed_all" => Change::RenamedAll {
+            moves: value["moves"]
+                .as_array()
+                .ok_or_else(|| anyhow!("Journal change is missing 'moves'"))?
+                .iter()
+                .map(|step| {
+                    let path = |name: &str| {
+                        step[name]
+                            .as_str()
+                            .map(PathBuf::from)
+                            .ok_or_else(|| anyhow!("Journal change is missing 'moves.{}'", name))
+                    };
+                    Ok((path("from")?, path("to")?))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        },
+        "trashed" => Change::Trashed {
+            path: path("path")?,
+            trash_id: field("trash_id")?.to_string(),
+        },
+        kind => return Err(anyhow!("Unknown journal change '{}'", kind)),
+    })
+}
@@ -1,8 +1,15 @@
+mod archive;
 mod command;
+mod detect;
 mod file_handler;
+mod journal;
+mod matcher;
+mod rename;
 mod test;
+mod trash;
 
 use crate::command::{Cli, Commands};
+use crate::journal::Journal;
 use anyhow::Result;
 use clap::Parser;
 use file_handler as FileHandler;
@@ -11,21 +18,24 @@ fn main() -> Result<()> {
     match cli.command {
         Commands::Tree(args) => FileHandler::run_tree(&args),
         Commands::Search(args) => FileHandler::run_search(&args),
-        Commands::Replace(args) => FileHandler::run_replace(&args),
+        Commands::Replace(args) => FileHandler::run_replace(&args, &Journal::open_default()?),
         Commands::Count(args) => FileHandler::run_count(&args),
-        Commands::Rename(args) => FileHandler::rename(&args),
+        Commands::Rename(args) => FileHandler::rename(&args, &Journal::open_default()?),
         Commands::List => FileHandler::list_current_dir(),
         Commands::ReadTableCSV(args) => FileHandler::read_csv_table(args),
-        Commands::Copy(args) => FileHandler::copy(&args),
-        Commands::DecompressZip(args) => FileHandler::decompress_zip(&args),
-        Commands::DecompressGz(args) => FileHandler::decompress_gz(&args),
-        Commands::CompressZip(args) => FileHandler::compress_to_zip(&args),
-        Commands::CompressGz(args) => FileHandler::compress_to_tar_gz(&args),
-        Commands::Remove(args) => FileHandler::remove(&args),
+        Commands::Copy(args) => FileHandler::copy(&args, &Journal::open_default()?),
+        Commands::Move(args) => FileHandler::move_path(&args, &Journal::open_default()?),
+        Commands::Decompress(args) => FileHandler::decompress(&args, &Journal::open_default()?),
+        Commands::Archive(args) => archive::run_archive(&args),
+        Commands::Identify(args) => detect::run_identify(&args),
+        Commands::Compress(args) => FileHandler::compress(&args),
+        Commands::Remove(args) => FileHandler::remove(&args, &Journal::open_default()?),
+        Commands::Trash(args) => trash::run_trash(&args),
+        Commands::Undo(args) => journal::run_undo(&args, &Journal::open_default()?),
+        Commands::History(args) => journal::run_history(&args, &Journal::open_default()?),
         Commands::CsvToJson(args) => FileHandler::csv_to_json(&args),
         Commands::JsonToCsv(args) => FileHandler::json_to_csv(&args),
         Commands::ReadFile(args )=> FileHandler::read_file(&args),
-        _ => Ok(()),
     }
 }
 
@@ -0,0 +1,244 @@
+use crate::command::SearchArgs;
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+use anyhow::{anyhow, Context};
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use std::fs;
+
+/// One match in a line: byte offsets and the index of the pattern that matched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub pattern: usize,
+}
+
+/// The patterns of a `search`, from the positional PATTERN, `-e` and `-f`,
+/// matched as regexes or, with `--fixed-strings`, as literals.
+pub struct Matcher {
+    count: usize,
+    engine: Engine,
+}
+
+enum Engine {
+    /// `any` is every pattern in its own group, in order; `groups[i]` is the
+    /// group of pattern `i`.
+    Regex {
+        set: RegexSet,
+        any: Regex,
+        groups: Vec<usize>,
+    },
+    /// `overlapping` finds every literal in a line, even one inside another.
+    Literals {
+        leftmost: AhoCorasick,
+        overlapping: AhoCorasick,
+    },
+}
+
+impl Matcher {
+    pub fn from_args(args: &SearchArgs) -> anyhow::Result<Matcher> {
+        let mut patterns: Vec<String> = args.pattern().into_iter().map(str::to_string).collect();
+        patterns.extend(args.regexp.iter().cloned());
+        // Where each pattern came from, for error messages.
+        let mut origins = vec![String::from("-e"); patterns.len()];
+        if args.pattern().is_some() {
+            origins[0] = String::from("PATTERN");
+        }
+
+        if let Some(file) = &args.patterns_file {
+            let contents = fs::read_to_string(file)
+                .with_context(|| format!("Failed to read patterns from {}", file.display()))?;
+            for (number, line) in contents.lines().enumerate() {
+                if !line.trim().is_empty() {
+                    patterns.push(line.to_string());
+                    origins.push(format!("line {} of {}", number + 1, file.display()));
+                }
+            }
+        }
+
+        if patterns.is_empty() {
+            return Err(anyhow!(
+                "Error: No search pattern given. Pass PATTERN, -e PATTERN or -f FILE."
+            ));
+        }
+
+        // Aho-Corasick only folds ASCII case and knows no word or line
+        // boundaries; for those, literals go through the regex engine, escaped.
+        let needs_regex = args.word_regexp
+            || args.line_regexp
+            || args.case_insensitive && patterns.iter().any(|p| !p.is_ascii());
+
+        let engine = if args.fixed_strings && !needs_regex {
+            let build = |kind| {
+                AhoCorasickBuilder::new()
+                    .ascii_case_insensitive(args.case_insensitive)
+                    .match_kind(kind)
+                    .build(&patterns)
+            };
+            Engine::Literals {
+                leftmost: build(MatchKind::LeftmostLongest)?,
+                overlapping: build(MatchKind::Standard)?,
+            }
+        } else {
+            let wrapped: Vec<String> = patterns
+                .iter()
+                .map(|pattern| {
+                    let pattern = if args.fixed_strings {
+                        regex::escape(pattern)
+                    } else {
+                        pattern.clone()
+                    };
+                    if args.line_regexp {
+                        format!("^(?:{})$", pattern)
+                    } else if args.word_regexp {
+                        format!(r"\b(?:{})\b", pattern)
+                    } else {
+                        pattern
+                    }
+                })
+                .collect();
+
+            // Each pattern is compiled on its own first, so an error names
+            // only the one at fault. Group numbers in `any` shift by the
+            // groups of the patterns before.
+            let mut groups = Vec::with_capacity(wrapped.len());
+            let mut next_group = 1;
+            for (i, pattern) in wrapped.iter().enumerate() {
+                let regex = RegexBuilder::new(pattern)
+                    .case_insensitive(args.case_insensitive)
+                    .dot_matches_new_line(args.multiline)
+                    .multi_line(args.multiline)
+                    .build()
+                    .with_context(|| {
+                        format!("Invalid regex pattern '{}' ({})", patterns[i], origins[i])
+                    })?;
+                groups.push(next_group);
+                next_group += regex.captures_len();
+            }
+
+            // In multiline mode `.` crosses lines and `^`/`$` still match
+            // at each line.
+            let set = RegexSetBuilder::new(&wrapped)
+                .case_insensitive(args.case_insensitive)
+                .dot_matches_new_line(args.multiline)
+                .multi_line(args.multiline)
+                .build()?;
+
+            // Only which group took part matters in `any`, so group names are
+            // dropped: patterns are free to use the same one.
+            let any = wrapped
+                .iter()
+                .map(|pattern| format!("({})", unname_groups(pattern)))
+                .collect::<Vec<_>>()
+                .join("|");
+            let any = RegexBuilder::new(&any)
+                .case_insensitive(args.case_insensitive)
+                .dot_matches_new_line(args.multiline)
+                .multi_line(args.multiline)
+                .build()?;
+
+            Engine::Regex { set, any, groups }
+        };
+
+        Ok(Matcher {
+            count: patterns.len(),
+            engine,
+        })
+    }
+
+    /// How many patterns there are.
+    pub fn pattern_count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        match &self.engine {
+            Engine::Regex { set, .. } => set.is_match(line),
+            Engine::Literals { leftmost, .. } => leftmost.is_match(line),
+        }
+    }
+
+    /// Non-overlapping matches in `line`, left to right.
+    pub fn find_iter(&self, line: &str) -> Vec<Span> {
+        match &self.engine {
+            Engine::Regex { any, groups, .. } => any
+                .captures_iter(line)
+                .filter_map(|caps| {
+                    let whole = caps.get(0)?;
+                    let pattern = groups.iter().position(|&g| caps.get(g).is_some())?;
+                    Some(Span {
+                        start: whole.start(),
+                        end: whole.end(),
+                        pattern,
+                    })
+                })
+                .collect(),
+            Engine::Literals { leftmost, .. } => leftmost
+                .find_iter(line)
+                .map(|mat| Span {
+                    start: mat.start(),
+                    end: mat.end(),
+                    pattern: mat.pattern().as_usize(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Indices of every pattern matching somewhere in `line`, ascending.
+    pub fn matching_patterns(&self, line: &str) -> Vec<usize> {
+        match &self.engine {
+            Engine::Regex { set, .. } => set.matches(line).into_iter().collect(),
+            Engine::Literals { overlapping, .. } => {
+                let mut found: Vec<usize> = overlapping
+                    .find_overlapping_iter(line)
+                    .map(|mat| mat.pattern().as_usize())
+                    .collect();
+                found.sort_unstable();
+                found.dedup();
+                found
+            }
+        }
+    }
+}
+
+/// `pattern` with each named group, `(?P<name>...)` or `(?<name>...)`, made
+/// a plain group. Groups keep their numbers.
+fn unname_groups(pattern: &str) -> String {
+    let bytes = pattern.as_bytes();
+    let mut out = String::with_capacity(pattern.len());
+    let mut copied = 0;
+    let mut class_depth = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'[' => {
+                class_depth += 1;
+                // A `]` first in a class is taken literally.
+                if bytes.get(i + 1) == Some(&b'^') {
+                    i += 1;
+                }
+                if bytes.get(i + 1) == Some(&b']') {
+                    i += 1;
+                }
+            }
+            b']' if class_depth > 0 => class_depth -= 1,
+            b'(' if class_depth == 0 => {
+                let rest = &pattern[i + 1..];
+                let named = ["?P<", "?<"].into_iter().any(|prefix| {
+                    rest.starts_with(prefix) && !rest[prefix.len()..].starts_with(['=', '!'])
+                });
+                if let Some(end) = rest.find('>').filter(|_| named) {
+                    // Keep the `(`, skip to the `>` closing the name.
+                    out.push_str(&pattern[copied..=i]);
+                    i += 1 + end;
+                    copied = i + 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    out.push_str(&pattern[copied..]);
+    out
+}
@@ -0,0 +1,570 @@
+use crate::command::RenameArgs;
+use crate::file_handler::expand_search_paths;
+use crate::journal::Journal;
+use anyhow::{anyhow, Context};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use regex::{Captures, Regex};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// `--from` and `--to` of a batch rename: the part of a file name matched by
+/// `from` is replaced by `to` with its placeholders filled in.
+pub struct Renamer {
+    from: Regex,
+    to: Vec<Part>,
+}
+
+enum Part {
+    Literal(String),
+    Field { name: String, spec: Option<String> },
+}
+
+/// What a name is built from besides the captures of `--from`.
+pub struct NameContext {
+    /// Position of the file among those matched, from 1.
+    pub counter: usize,
+    pub modified: DateTime<Local>,
+}
+
+impl Renamer {
+    /// Parses `to` against the groups of `from`, so a bad placeholder is
+    /// reported before any file is looked at.
+    pub fn new(from: &str, to: &str) -> anyhow::Result<Renamer> {
+        let from = Regex::new(from).with_context(|| format!("Invalid --from regex: {}", from))?;
+        let to = parse_template(to)?;
+
+        for part in &to {
+            let Part::Field { name, spec } = part else {
+                continue;
+            };
+            let known = matches!(name.as_str(), "n" | "date" | "ext" | "stem")
+                || name
+                    .parse::<usize>()
+                    .is_ok_and(|index| index < from.captures_len())
+                || from.capture_names().flatten().any(|group| group == name);
+            if !known {
+                return Err(anyhow!(
+                    "Error: Unknown placeholder '{{{}}}' in --to; use a group of --from, n, date, ext or stem.",
+                    name
+                ));
+            }
+            if let Some(spec) = spec {
+                check_spec(name, spec)?;
+            }
+        }
+
+        Ok(Renamer { from, to })
+    }
+
+    /// The new name of the file `name`, or `None` when `--from` does not match it.
+    pub fn new_name(&self, name: &str, context: &NameContext) -> Option<String> {
+        let caps = self.from.captures(name)?;
+        let whole = caps.get(0).unwrap();
+
+        let mut expanded = String::new();
+        for part in &self.to {
+            match part {
+                Part::Literal(text) => expanded.push_str(text),
+                Part::Field { name: field, spec } => {
+                    let value = field_value(field, spec.as_deref(), name, &caps, context);
+                    expanded.push_str(&apply_spec(&value, spec.as_deref()));
+                }
+            }
+        }
+
+        Some(format!(
+            "{}{}{}",
+            &name[..whole.start()],
+            expanded,
+            &name[whole.end()..]
+        ))
+    }
+}
+
+/// Splits a template into literals and `{name}` / `{name:spec}` fields;
+/// `{{` and `}}` stand for braces.
+fn parse_template(template: &str) -> anyhow::Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut field = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    field.push(c);
+                }
+                if !closed {
+                    return Err(anyhow!("Error: Unclosed '{{' in --to '{}'.", template));
+                }
+                if !literal.is_empty() {
+                    parts.push(Part::Literal(std::mem::take(&mut literal)));
+                }
+                let (name, spec) = match field.split_once(':') {
+                    Some((name, spec)) => (name, Some(spec.to_string())),
+                    None => (field.as_str(), None),
+                };
+                parts.push(Part::Field {
+                    name: name.to_string(),
+                    spec,
+                });
+            }
+            '}' => return Err(anyhow!("Error: Unmatched '}}' in --to '{}'.", template)),
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+fn check_spec(name: &str, spec: &str) -> anyhow::Result<()> {
+    let plain = is_case(spec) || is_width(spec);
+    if name == "date" && !plain {
+        if StrftimeItems::new(spec).any(|item| matches!(item, Item::Error)) {
+            return Err(anyhow!("Error: Invalid date format '{}' in --to.", spec));
+        }
+    } else if !plain {
+        return Err(anyhow!(
+            "Error: Unknown format '{}' for '{{{}}}'; use a width like 03, lower, upper, snake or kebab.",
+            spec,
+            name
+        ));
+    }
+    Ok(())
+}
+
+fn field_value(
+    field: &str,
+    spec: Option<&str>,
+    name: &str,
+    caps: &Captures,
+    context: &NameContext,
+) -> String {
+    let path = Path::new(name);
+    let group = match field.parse::<usize>() {
+        Ok(index) => caps.get(index),
+        Err(_) => caps.name(field),
+    };
+
+    match (field, group) {
+        (_, Some(group)) => group.as_str().to_string(),
+        ("n", None) => context.counter.to_string(),
+        ("date", None) => {
+            let format = match spec {
+                Some(spec) if !is_case(spec) && !is_width(spec) => spec,
+                _ => "%Y-%m-%d",
+            };
+            let mut date = String::new();
+            // The format was checked when the template was parsed.
+            let _ = write!(date, "{}", context.modified.format(format));
+            date
+        }
+        ("ext", None) => path
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        ("stem", None) => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        // A group that did not take part in the match.
+        _ => String::new(),
+    }
+}
+
+fn is_case(spec: &str) -> bool {
+    matches!(spec, "lower" | "upper" | "snake" | "kebab")
+}
+
+fn is_width(spec: &str) -> bool {
+    !spec.is_empty() && spec.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Applies a width (zero-padding numbers) or a case transform to `value`.
+fn apply_spec(value: &str, spec: Option<&str>) -> String {
+    match spec {
+        Some("lower") => value.to_lowercase(),
+        Some("upper") => value.to_uppercase(),
+        Some("snake") => split_words(value).join("_"),
+        Some("kebab") => split_words(value).join("-"),
+        Some(spec) if is_width(spec) => {
+            let width: usize = spec.parse().unwrap_or(0);
+            if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+                format!("{:0>width$}", value, width = width)
+            } else {
+                value.to_string()
+            }
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Lowercase words of `value`, split at anything that is not a letter or
+/// digit and where camel case starts a new word (`HTMLFile` is `html file`).
+pub fn split_words(value: &str) -> Vec<String> {
+    let chars: Vec<char> = value.chars().collect();
+    let mut words = Vec::new();
+    let mut word = String::new();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        let prev = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1);
+        let starts_word = c.is_uppercase()
+            && (prev.is_some_and(|p| p.is_lowercase() || p.is_numeric())
+                || (prev.is_some_and(char::is_uppercase)
+                    && next.is_some_and(|n| n.is_lowercase())));
+        if starts_word && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        word.extend(c.to_lowercase());
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Files a batch rename goes over: those directly in a directory, a file or
+/// what a glob matches, sorted so counters follow the names.
+fn batch_files(input: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in expand_search_paths(&[input.to_string()], &[])? {
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    files.push(entry.path());
+                }
+            }
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Everything that stops `moves` from being done as one batch: missing
+/// sources, two files given the same name, and names already taken by a file
+/// that is not itself being renamed.
+pub fn conflicts(moves: &[(PathBuf, PathBuf)]) -> Vec<String> {
+    let sources: HashSet<&PathBuf> = moves.iter().map(|(from, _)| from).collect();
+    let mut targets: HashMap<&PathBuf, &PathBuf> = HashMap::new();
+    let mut conflicts = Vec::new();
+
+    for (from, to) in moves {
+        if fs::symlink_metadata(from).is_err() {
+            conflicts.push(format!("'{}' does not exist.", from.display()));
+        }
+        if let Some(other) = targets.insert(to, from) {
+            conflicts.push(format!(
+                "'{}' and '{}' would both become '{}'.",
+                other.display(),
+                from.display(),
+                to.display()
+            ));
+        } else if !sources.contains(to) && fs::symlink_metadata(to).is_ok() {
+            conflicts.push(format!("'{}' already exists.", to.display()));
+        }
+    }
+    conflicts
+}
+
+/// Does every move of `moves` or none of them. Each file first goes to a
+/// temp name next to it, so names can be swapped or passed around in a
+/// cycle; when a step fails the ones done are taken back in reverse.
+pub fn rename_all(moves: &[(PathBuf, PathBuf)]) -> anyhow::Result<()> {
+    let mut done = Vec::new();
+    let Err(e) = rename_steps(moves, &mut done) else {
+        return Ok(());
+    };
+
+    let stuck: Vec<String> = done
+        .iter()
+        .rev()
+        .filter(|(from, to)| fs::rename(to, from).is_err())
+        .map(|(from, to)| format!("'{}' is left as '{}'", from.display(), to.display()))
+        .collect();
+    if stuck.is_empty() {
+        Err(anyhow!(
+            "Error: Renaming failed, nothing was changed: {}",
+            e
+        ))
+    } else {
+        Err(anyhow!(
+            "Error: Renaming failed ({}) and could not be rolled back:\n  {}",
+            e,
+            stuck.join("\n  ")
+        ))
+    }
+}
+
+fn rename_steps(
+    moves: &[(PathBuf, PathBuf)],
+    done: &mut Vec<(PathBuf, PathBuf)>,
+) -> anyhow::Result<()> {
+    let mut staged = Vec::new();
+    for (i, (from, to)) in moves.iter().enumerate() {
+        let temp = temp_name(from, i);
+        fs::rename(from, &temp).with_context(|| format!("'{}'", from.display()))?;
+        done.push((from.clone(), temp.clone()));
+        staged.push((temp, to));
+    }
+
+    for (temp, to) in staged {
+        fs::rename(&temp, to).with_context(|| format!("'{}'", to.display()))?;
+        done.push((temp, to.clone()));
+    }
+    Ok(())
+}
+
+fn temp_name(path: &Path, index: usize) -> PathBuf {
+    let mut counter = 0;
+    loop {
+        let name = format!(
+            ".clap-tool-file-rename-{}-{}-{}",
+            std::process::id(),
+            index,
+            counter
+        );
+        let temp = path.with_file_name(name);
+        match fs::symlink_metadata(&temp) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return temp,
+            _ => counter += 1,
+        }
+    }
+}
+
+/// Prints `moves` as a two-column table of old and new paths.
+pub fn print_moves(moves: &[(PathBuf, PathBuf)]) {
+    let width = moves
+        .iter()
+        .map(|(from, _)| from.display().to_string().chars().count())
+        .chain(["Old name".len()])
+        .max()
+        .unwrap_or(0);
+
+    println!("{:<width$}  New name", "Old name", width = width);
+    println!("{:-<width$}  {:-<8}", "", "", width = width);
+    for (from, to) in moves {
+        println!(
+            "{:<width$}  {}",
+            from.display().to_string(),
+            to.display(),
+            width = width
+        );
+    }
+}
+
+/// `rename <DIR|GLOB> --from <REGEX> --to <TEMPLATE>`: renames every matching
+/// file in one go, after checking the whole batch.
+pub fn run_batch(args: &RenameArgs, from: &str, to: &str, journal: &Journal) -> anyhow::Result<()> {
+    let renamer = Renamer::new(from, to)?;
+
+    let mut moves = Vec::new();
+    let mut matched = 0;
+    for path in batch_files(&args.old_file_name)? {
+        let Some(name) = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+        else {
+            continue;
+        };
+        let context = NameContext {
+            counter: matched + 1,
+            modified: fs::metadata(&path)?.modified()?.into(),
+        };
+        let Some(new_name) = renamer.new_name(&name, &context) else {
+            continue;
+        };
+        matched += 1;
+        if new_name.is_empty() || new_name == "." || new_name == ".." || new_name.contains('/') {
+            return Err(anyhow!(
+                "Error: '{}' would be renamed to the invalid name '{}'.",
+                path.display(),
+                new_name
+            ));
+        }
+        if new_name != name {
+            let new_path = path.with_file_name(&new_name);
+            moves.push((path, new_path));
+        }
+    }
+
+    apply_moves(&moves, args.dry_run, journal)
+}
+
+/// Prints `moves` and, unless `dry_run` or something is in the way, does
+/// them all as one recorded operation.
+fn apply_moves(
+    moves: &[(PathBuf, PathBuf)],
+    dry_run: bool,
+    journal: &Journal,
+) -> anyhow::Result<()> {
+    if moves.is_empty() {
+        println!("Nothing to rename.");
+        return Ok(());
+    }
+
+    print_moves(moves);
+    let conflicts = conflicts(moves);
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "Error: Cannot rename:\n  {}",
+            conflicts.join("\n  ")
+        ));
+    }
+
+    if dry_run {
+        println!("{} file(s) would be renamed", moves.len());
+        return Ok(());
+    }
+
+    let op = journal.start();
+    op.rename_all(moves, || rename_all(moves))?;
+    println!("Renamed {} file(s)", moves.len());
+    Ok(())
+}
+
+/// Names of the entries directly in `dir`, sorted.
+pub fn entry_names(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("Error: Cannot read '{}'", dir.display()))?
+    {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            return Err(anyhow!(
+                "Error: '{}' is not valid UTF-8 and cannot be edited.",
+                name.to_string_lossy()
+            ));
+        };
+        if name.contains(['\n', '\r']) {
+            return Err(anyhow!(
+                "Error: '{}' has a line break in its name and cannot be edited.",
+                name.escape_debug()
+            ));
+        }
+        names.push(name.to_string());
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The renames in `dir` asked for by `edited`, which holds the new name of
+/// each of `names` on the same line. Every bad line is reported at once.
+pub fn edited_moves(
+    dir: &Path,
+    names: &[String],
+    edited: &str,
+) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+    let lines: Vec<&str> = edited
+        .lines()
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect();
+    if lines.len() != names.len() {
+        return Err(anyhow!(
+            "Error: Expected {} line(s), one per name, but got {}; nothing was renamed.",
+            names.len(),
+            lines.len()
+        ));
+    }
+
+    let mut moves = Vec::new();
+    let mut invalid = Vec::new();
+    for (number, (name, line)) in names.iter().zip(&lines).enumerate() {
+        if line.is_empty() || *line == "." || *line == ".." || line.contains('/') {
+            invalid.push(format!(
+                "line {}: '{}' is not a valid name.",
+                number + 1,
+                line
+            ));
+        } else if line != name {
+            moves.push((dir.join(name), dir.join(line)));
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(anyhow!("Error: Cannot rename:\n  {}", invalid.join("\n  ")));
+    }
+    Ok(moves)
+}
+
+/// `rename --edit <DIR>`: opens the names in `dir` in `$EDITOR` and renames
+/// each entry to what its line says afterwards.
+pub fn run_edit(args: &RenameArgs, journal: &Journal) -> anyhow::Result<()> {
+    let dir = Path::new(&args.old_file_name);
+    if !dir.is_dir() {
+        return Err(anyhow!(
+            "Error: '{}' is not a directory.",
+            args.old_file_name
+        ));
+    }
+
+    let names = entry_names(dir)?;
+    if names.is_empty() {
+        println!("Nothing to rename.");
+        return Ok(());
+    }
+
+    let mut file = tempfile::Builder::new()
+        .prefix("clap-tool-file-rename-")
+        .suffix(".txt")
+        .tempfile()?;
+    for name in &names {
+        writeln!(file, "{}", name)?;
+    }
+    file.flush()?;
+
+    let editor = std::env::var("EDITOR")
+        .or_else(|_| std::env::var("VISUAL"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // `$EDITOR` may carry arguments, as in `code --wait`.
+    let mut words = editor.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| anyhow!("Error: $EDITOR is empty."))?;
+    let status = Command::new(program)
+        .args(words)
+        .arg(file.path())
+        .status()
+        .with_context(|| format!("Error: Cannot run the editor '{}'", editor))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "Error: The editor exited with {}; nothing was renamed.",
+            status
+        ));
+    }
+
+    let edited = fs::read_to_string(file.path())?;
+    let moves = edited_moves(dir, &names, &edited)?;
+    apply_moves(&moves, args.dry_run, journal)
+}
@@ -1,9 +1,52 @@
 #[cfg(test)]
 mod tests {
-    use crate::command::ReplaceArgs;
-    use crate::FileHandler::{format_size, run_replace};
+    use crate::archive::{
+        detect_format, format_from_name, list_entries, test_archive, Codec, Format,
+    };
+    use crate::command::{
+        BinaryMode, CompressArgs, DecompressArgs, JsonToCsvArgs, LineRange, MoveArgs, ParallelArgs,
+        RemoveArgs, ReplaceArgs, SearchArgs, SourceFilterArgs,
+    };
+    use crate::detect::{identify, is_binary, is_tar_header, sniff, FileKind};
+    use crate::journal::{Change, Journal};
+    use crate::matcher::{Matcher, Span};
+    use crate::rename::{
+        conflicts, edited_moves, entry_names, rename_all, split_words, NameContext, Renamer,
+    };
+    use crate::trash::{check_id, parse_age, Trash};
+    use crate::FileHandler::{
+        apply_edits, collect_sources, compress, compress_to_zip, decompress, decompress_zip,
+        diff_hunks, format_size, json_to_csv, move_by_copy, move_path, parse_line_range,
+        parse_size, plan_removal, remove, run_replace, search_path, search_reader, search_walker,
+        walk_files_parallel, write_atomic, write_atomic_no_follow, write_entry, EntryFilter,
+        Replacer, Review,
+    };
     use std::fs;
-    use tempfile::tempdir;
+    use tempfile::{tempdir, TempDir};
+
+    /// A journal and trash in a temp dir of their own, for the commands the
+    /// tests run to record into instead of the user's.
+    struct TestJournal {
+        journal: Journal,
+        _dir: TempDir,
+    }
+
+    impl std::ops::Deref for TestJournal {
+        type Target = Journal;
+
+        fn deref(&self) -> &Journal {
+            &self.journal
+        }
+    }
+
+    fn test_journal() -> anyhow::Result<TestJournal> {
+        let dir = tempdir()?;
+        let journal = Journal::new(
+            dir.path().join("journal"),
+            Trash::new(dir.path().join("trash")),
+        );
+        Ok(TestJournal { journal, _dir: dir })
+    }
 
     #[test]
     fn test_format_size() {
@@ -15,6 +58,7 @@ mod tests {
 
     #[test]
     fn test_search_replace() -> anyhow::Result<()> {
+        let journal = test_journal()?;
         let dir = tempdir()?;
         let file_path = dir.path().join("test.txt");
         fs::write(&file_path, "hello world\nhello rust")?;
@@ -23,15 +67,2014 @@ mod tests {
             path: dir.path().to_path_buf(),
             old_string: "hello".to_string(),
             new_string: "hi".to_string(),
+            regex: false,
+            case_insensitive: false,
+            max_replacements: None,
+            lines: None,
             dry_run: false,
+            interactive: false,
             backup: true,
+            binary: BinaryMode::Skip,
+            parallel: ParallelArgs {
+                threads: Some(2),
+                sort: false,
+            },
         };
 
-        run_replace(&args)?;
+        run_replace(&args, &journal)?;
 
         let content = fs::read_to_string(&file_path)?;
         assert_eq!(content, "hi world\nhi rust");
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_move_into_existing_dir() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let src = dir.path().join("a.txt");
+        let dest_dir = dir.path().join("dest");
+        fs::write(&src, "data")?;
+        fs::create_dir(&dest_dir)?;
+
+        move_path(
+            &MoveArgs {
+                old_des: src.clone(),
+                new_des: dest_dir.clone(),
+                recursive: false,
+            },
+            &journal,
+        )?;
+
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(dest_dir.join("a.txt"))?, "data");
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_dir_requires_recursive() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir(&src)?;
+
+        let args = MoveArgs {
+            old_des: src.clone(),
+            new_des: dir.path().join("renamed"),
+            recursive: false,
+        };
+        assert!(move_path(&args, &journal).is_err());
+        assert!(src.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_by_copy_dir() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("nested"))?;
+        fs::write(src.join("nested/b.txt"), "b")?;
+
+        let dest = dir.path().join("dest");
+        move_by_copy(&src, &dest)?;
+
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(dest.join("nested/b.txt"))?, "b");
+        Ok(())
+    }
+
+    #[test]
+    fn test_trash_put_and_restore() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let trash = Trash::new(dir.path().join("trash"));
+        let file_path = dir.path().join("data").join("keep.txt");
+        fs::create_dir_all(file_path.parent().unwrap())?;
+        fs::write(&file_path, "keep me")?;
+
+        let id = trash.put(&file_path)?;
+        assert!(!file_path.exists());
+
+        let entries = trash.list()?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, id);
+        assert_eq!(entries[0].original_path, std::path::absolute(&file_path)?);
+
+        trash.restore(&id)?;
+        assert_eq!(fs::read_to_string(&file_path)?, "keep me");
+        assert!(trash.list()?.is_empty());
+
+        // Ids never reach outside the trash.
+        for id in ["../../x", "..", ".", "a/b", "/etc/passwd", ""] {
+            assert!(check_id(id).is_err(), "{}", id);
+            assert!(trash.restore(id).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_trash_empty_older_than() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let trash = Trash::new(dir.path().join("trash"));
+        let sub_dir = dir.path().join("sub");
+        fs::create_dir(&sub_dir)?;
+        fs::write(sub_dir.join("a.txt"), "a")?;
+
+        trash.put(&sub_dir)?;
+        assert_eq!(trash.empty(Some(parse_age("1d")?))?, 0);
+        assert_eq!(trash.empty(None)?, 1);
+        assert!(trash.list()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_age() -> anyhow::Result<()> {
+        assert_eq!(parse_age("90s")?, chrono::Duration::seconds(90));
+        assert_eq!(parse_age("12h")?, chrono::Duration::hours(12));
+        assert_eq!(parse_age("2w")?, chrono::Duration::weeks(2));
+        assert!(parse_age("3y").is_err());
+        Ok(())
+    }
+
+    fn remove_args(path: &std::path::Path) -> RemoveArgs {
+        RemoveArgs {
+            path: path.display().to_string(),
+            files_only: false,
+            dirs_only: false,
+            recursive: false,
+            force: true,
+            dry_run: false,
+            permanent: true,
+        }
+    }
+
+    #[test]
+    fn test_remove_dirs_only_nested() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("empty/deeper/deepest"))?;
+        fs::create_dir_all(root.join("mixed/empty_child"))?;
+        fs::write(root.join("mixed/keep.txt"), "keep")?;
+
+        let args = RemoveArgs {
+            dirs_only: true,
+            ..remove_args(root)
+        };
+        let mut targets: Vec<_> = plan_removal(root, &args)?
+            .into_iter()
+            .map(|t| t.path)
+            .collect();
+        targets.sort();
+        assert_eq!(
+            targets,
+            vec![root.join("empty"), root.join("mixed/empty_child")]
+        );
+
+        remove(&args, &journal)?;
+        assert!(!root.join("empty").exists());
+        assert!(!root.join("mixed/empty_child").exists());
+        assert!(root.join("mixed/keep.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_files_only_keeps_tree() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let root = dir.path().join("data");
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("a.txt"), "a")?;
+        fs::write(root.join("sub/b.txt"), "b")?;
+
+        remove(
+            &RemoveArgs {
+                files_only: true,
+                ..remove_args(&root)
+            },
+            &journal,
+        )?;
+
+        assert!(root.join("sub").is_dir());
+        assert_eq!(fs::read_dir(root.join("sub"))?.count(), 0);
+        assert!(!root.join("a.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_glob_and_dry_run() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.log"), "a")?;
+        fs::write(dir.path().join("b.log"), "b")?;
+        fs::write(dir.path().join("c.txt"), "c")?;
+        let pattern = dir.path().join("*.log");
+
+        remove(
+            &RemoveArgs {
+                dry_run: true,
+                ..remove_args(&pattern)
+            },
+            &journal,
+        )?;
+        assert!(dir.path().join("a.log").exists());
+
+        remove(&remove_args(&pattern), &journal)?;
+        assert!(!dir.path().join("a.log").exists());
+        assert!(!dir.path().join("b.log").exists());
+        assert!(dir.path().join("c.txt").exists());
+
+        // Only what goes to the trash can be brought back, so only that is recorded.
+        assert!(journal.list()?.is_empty());
+        let text = dir.path().join("c.txt");
+        remove(
+            &RemoveArgs {
+                permanent: false,
+                ..remove_args(&text)
+            },
+            &journal,
+        )?;
+        assert!(!text.exists());
+        journal.undo(&journal.list()?[0].id)?;
+        assert!(text.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_dir_requires_flag() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        assert!(remove(&remove_args(dir.path()), &journal).is_err());
+        assert!(dir.path().exists());
+        Ok(())
+    }
+
+    fn decompress_args(path: &std::path::Path, output_dir: &std::path::Path) -> DecompressArgs {
+        DecompressArgs {
+            path: path.to_path_buf(),
+            output_dir: Some(output_dir.to_path_buf()),
+            strip_unsafe: false,
+            skip_existing: false,
+            overwrite: false,
+            rename: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            stdout: false,
+        }
+    }
+
+    fn write_zip(path: &std::path::Path, entries: &[(&str, &str)]) -> anyhow::Result<()> {
+        use std::io::Write;
+        use zip::write::FileOptions;
+
+        let mut writer = zip::ZipWriter::new(fs::File::create(path)?);
+        let options = FileOptions::default()
+            .unix_permissions(0o600)
+            .last_modified_time(zip::DateTime::from_date_and_time(2020, 1, 2, 3, 4, 6).unwrap());
+        for (name, contents) in entries {
+            writer.start_file(*name, options)?;
+            writer.write_all(contents.as_bytes())?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_zip_rejects_unsafe_entries() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("evil.zip");
+        write_zip(&zip_path, &[("ok/a.txt", "a"), ("../../evil.txt", "x")])?;
+        let out = dir.path().join("out");
+
+        assert!(decompress_zip(&decompress_args(&zip_path, &out), &journal).is_err());
+        assert!(!out.join("ok/a.txt").exists());
+
+        decompress_zip(
+            &DecompressArgs {
+                strip_unsafe: true,
+                ..decompress_args(&zip_path, &out)
+            },
+            &journal,
+        )?;
+        assert_eq!(fs::read_to_string(out.join("evil.txt"))?, "x");
+        assert!(!dir.path().join("evil.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_zip_existing_policies() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("a.zip");
+        write_zip(&zip_path, &[("a.txt", "new")])?;
+        let out = dir.path().join("out");
+        fs::create_dir(&out)?;
+        fs::write(out.join("a.txt"), "old")?;
+
+        decompress_zip(
+            &DecompressArgs {
+                skip_existing: true,
+                ..decompress_args(&zip_path, &out)
+            },
+            &journal,
+        )?;
+        assert_eq!(fs::read_to_string(out.join("a.txt"))?, "old");
+
+        decompress_zip(
+            &DecompressArgs {
+                rename: true,
+                ..decompress_args(&zip_path, &out)
+            },
+            &journal,
+        )?;
+        assert_eq!(fs::read_to_string(out.join("a (1).txt"))?, "new");
+
+        decompress_zip(
+            &DecompressArgs {
+                overwrite: true,
+                ..decompress_args(&zip_path, &out)
+            },
+            &journal,
+        )?;
+        assert_eq!(fs::read_to_string(out.join("a.txt"))?, "new");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_decompress_zip_restores_mode_and_mtime() -> anyhow::Result<()> {
+        use chrono::{Local, TimeZone};
+        use std::os::unix::fs::PermissionsExt;
+
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("a.zip");
+        write_zip(&zip_path, &[("a.txt", "a")])?;
+        let out = dir.path().join("out");
+
+        decompress_zip(&decompress_args(&zip_path, &out), &journal)?;
+
+        let md = fs::metadata(out.join("a.txt"))?;
+        assert_eq!(md.permissions().mode() & 0o777, 0o600);
+        let expected = Local.with_ymd_and_hms(2020, 1, 2, 3, 4, 6).unwrap();
+        assert_eq!(md.modified()?, std::time::SystemTime::from(expected));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_decompress_zip_drops_special_bits_and_replaces_links() -> anyhow::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("a.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&zip_path)?);
+        let options = zip::write::FileOptions::default().unix_permissions(0o4755);
+        writer.start_file("run.sh", options)?;
+        writer.write_all(b"new")?;
+        writer.finish()?;
+
+        let out = dir.path().join("out");
+        fs::create_dir(&out)?;
+        let outside = dir.path().join("outside.sh");
+        fs::write(&outside, "outside")?;
+        std::os::unix::fs::symlink(&outside, out.join("run.sh"))?;
+
+        decompress_zip(
+            &DecompressArgs {
+                overwrite: true,
+                ..decompress_args(&zip_path, &out)
+            },
+            &journal,
+        )?;
+
+        assert_eq!(fs::read_to_string(&outside)?, "outside");
+        let md = fs::symlink_metadata(out.join("run.sh"))?;
+        assert!(md.is_file());
+        assert_eq!(md.permissions().mode() & 0o7777, 0o755);
+        Ok(())
+    }
+
+    fn write_tar_gz(path: &std::path::Path, entries: &[(&str, &str)]) -> anyhow::Result<()> {
+        let encoder =
+            flate2::write::GzEncoder::new(fs::File::create(path)?, flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            // Written by hand so tests can produce names `set_path` would refuse.
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o640);
+            header.set_mtime(1_600_000_000);
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes())?;
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_tar_gz() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let archive = dir.path().join("a.tar.gz");
+        write_tar_gz(&archive, &[("top.txt", "top"), ("sub/inner.txt", "inner")])?;
+        let out = dir.path().join("out");
+
+        decompress(&decompress_args(&archive, &out), &journal)?;
+
+        assert_eq!(fs::read_to_string(out.join("top.txt"))?, "top");
+        let inner = out.join("sub/inner.txt");
+        assert_eq!(fs::read_to_string(&inner)?, "inner");
+        assert_eq!(
+            fs::metadata(&inner)?.modified()?,
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_tar_gz_rejects_unsafe_entries() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let archive = dir.path().join("evil.tar.gz");
+        write_tar_gz(&archive, &[("ok.txt", "ok"), ("../evil.txt", "x")])?;
+        let out = dir.path().join("out");
+
+        assert!(decompress(&decompress_args(&archive, &out), &journal).is_err());
+        assert!(!out.join("ok.txt").exists());
+        assert!(!dir.path().join("evil.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_plain_gz_next_to_input() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let gz_path = dir.path().join("app.log.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&gz_path)?,
+            flate2::Compression::default(),
+        );
+        encoder.write_all(b"line 1\nline 2\n")?;
+        encoder.finish()?;
+
+        decompress(
+            &DecompressArgs {
+                output_dir: None,
+                ..decompress_args(&gz_path, dir.path())
+            },
+            &journal,
+        )?;
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("app.log"))?,
+            "line 1\nline 2\n"
+        );
+
+        // A bare file name is in the current dir, the crate root for tests.
+        let gz = tempfile::Builder::new()
+            .prefix(".test-")
+            .suffix(".log.gz")
+            .tempfile_in(".")?;
+        fs::copy(&gz_path, gz.path())?;
+        let name = gz.path().file_name().unwrap().to_os_string();
+        let out = tempfile::TempPath::from_path(std::path::Path::new(&name).with_extension(""));
+        decompress(
+            &DecompressArgs {
+                output_dir: None,
+                ..decompress_args(std::path::Path::new(&name), dir.path())
+            },
+            &journal,
+        )?;
+        assert_eq!(fs::read_to_string(&out)?, "line 1\nline 2\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_list_zip_and_tar_gz() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("a.zip");
+        write_zip(
+            &zip_path,
+            &[("a.txt", "aaaaaaaaaaaaaaaaaaaa"), ("b/c.txt", "c")],
+        )?;
+
+        let entries = list_entries(&zip_path)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.txt");
+        assert_eq!(entries[0].size, 20);
+        assert!(entries[0].ratio().unwrap() < 1.0);
+
+        let tar_path = dir.path().join("a.tar.gz");
+        write_tar_gz(&tar_path, &[("x.txt", "xyz")])?;
+        let entries = list_entries(&tar_path)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].size, 3);
+        assert_eq!(entries[0].compressed_size, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_test_detects_bad_crc() -> anyhow::Result<()> {
+        use std::io::Write;
+        use zip::write::FileOptions;
+
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("a.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&zip_path)?);
+        writer.start_file(
+            "a.txt",
+            FileOptions::default().compression_method(zip::CompressionMethod::Stored),
+        )?;
+        writer.write_all(b"hello archive")?;
+        writer.finish()?;
+        assert_eq!(test_archive(&zip_path)?, 0);
+
+        let mut bytes = fs::read(&zip_path)?;
+        let pos = bytes
+            .windows(13)
+            .position(|w| w == b"hello archive")
+            .unwrap();
+        bytes[pos] = b'j';
+        fs::write(&zip_path, bytes)?;
+        assert_eq!(test_archive(&zip_path)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_filter() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let filter = EntryFilter::from_args(&DecompressArgs {
+            include: vec!["*.toml".to_string(), "docs/*".to_string()],
+            exclude: vec!["*/test.toml".to_string()],
+            ..decompress_args(dir.path(), dir.path())
+        })?;
+
+        assert!(filter.matches("release/config/app.toml"));
+        assert!(filter.matches("./docs/readme.md"));
+        assert!(!filter.matches("release/config/test.toml"));
+        assert!(!filter.matches("bin/app"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_selected_entries() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("bundle.zip");
+        write_zip(
+            &zip_path,
+            &[
+                ("bin/app", "bin"),
+                ("conf/app.toml", "conf"),
+                ("../evil.txt", "x"),
+            ],
+        )?;
+        let tar_path = dir.path().join("bundle.tar.gz");
+        write_tar_gz(&tar_path, &[("bin/app", "bin"), ("conf/app.toml", "conf")])?;
+
+        let zip_out = dir.path().join("zip_out");
+        decompress_zip(
+            &DecompressArgs {
+                include: vec!["conf/*".to_string()],
+                ..decompress_args(&zip_path, &zip_out)
+            },
+            &journal,
+        )?;
+        assert_eq!(fs::read_to_string(zip_out.join("conf/app.toml"))?, "conf");
+        assert!(!zip_out.join("bin").exists());
+
+        let tar_out = dir.path().join("tar_out");
+        decompress(
+            &DecompressArgs {
+                exclude: vec!["*.toml".to_string()],
+                ..decompress_args(&tar_path, &tar_out)
+            },
+            &journal,
+        )?;
+        assert!(tar_out.join("bin/app").exists());
+        assert!(!tar_out.join("conf/app.toml").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decompress_one_entry_to_stdout() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let zip_path = dir.path().join("bundle.zip");
+        write_zip(&zip_path, &[("bin/app", "bin"), ("conf/app.toml", "conf")])?;
+        let tar_path = dir.path().join("bundle.tar.gz");
+        write_tar_gz(&tar_path, &[("bin/app", "bin"), ("conf/app.toml", "conf")])?;
+
+        for archive in [&zip_path, &tar_path] {
+            let args = |include: &str| DecompressArgs {
+                include: vec![include.to_string()],
+                stdout: true,
+                ..decompress_args(archive, dir.path())
+            };
+
+            let mut out = Vec::new();
+            write_entry(&args("conf/*"), &mut out)?;
+            assert_eq!(out, b"conf");
+
+            let err = write_entry(&args("*"), &mut Vec::new()).unwrap_err();
+            assert!(err.to_string().contains("exactly one entry"), "{}", err);
+            let err = write_entry(&args("nope/*"), &mut Vec::new()).unwrap_err();
+            assert!(err.to_string().contains("No file entry"), "{}", err);
+        }
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_zip_round_trip_methods() -> anyhow::Result<()> {
+        use crate::command::ZipMethod;
+
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let src = dir.path().join("project");
+        fs::create_dir_all(src.join("empty"))?;
+        fs::write(src.join("data.txt"), "data ".repeat(100))?;
+
+        for (method, level) in [
+            (ZipMethod::Stored, None),
+            (ZipMethod::Deflate, Some(9)),
+            (ZipMethod::Bzip2, Some(1)),
+            (ZipMethod::Zstd, Some(3)),
+        ] {
+            let zip_path = dir.path().join(format!("{:?}.zip", method));
+            compress_to_zip(&CompressArgs {
+                files: vec![src.display().to_string()],
+                output_compress: zip_path.display().to_string(),
+                level,
+                method: Some(method),
+                source_filter: no_source_filter(),
+            })?;
+
+            let out = dir.path().join(format!("{:?}_out", method));
+            decompress_zip(&decompress_args(&zip_path, &out), &journal)?;
+            assert_eq!(
+                fs::read_to_string(out.join("project/data.txt"))?,
+                "data ".repeat(100)
+            );
+            assert!(out.join("project/empty").is_dir());
+        }
+
+        let bad_level = CompressArgs {
+            files: vec![src.display().to_string()],
+            output_compress: dir.path().join("bad.zip").display().to_string(),
+            level: Some(5),
+            method: Some(ZipMethod::Stored),
+            source_filter: no_source_filter(),
+        };
+        assert!(compress_to_zip(&bad_level).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_compress_zip_keeps_mode_and_mtime() -> anyhow::Result<()> {
+        use crate::command::ZipMethod;
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let script = dir.path().join("run.sh");
+        fs::write(&script, "#!/bin/sh\n")?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o751))?;
+        // DOS timestamps have a two second resolution.
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&script)?
+            .set_modified(mtime)?;
+
+        let zip_path = dir.path().join("a.zip");
+        compress_to_zip(&CompressArgs {
+            files: vec![script.display().to_string()],
+            output_compress: zip_path.display().to_string(),
+            level: None,
+            method: Some(ZipMethod::Deflate),
+            source_filter: no_source_filter(),
+        })?;
+
+        let out = dir.path().join("out");
+        decompress_zip(&decompress_args(&zip_path, &out), &journal)?;
+        let md = fs::metadata(out.join("run.sh"))?;
+        assert_eq!(md.permissions().mode() & 0o777, 0o751);
+        assert_eq!(md.modified()?, mtime);
+        Ok(())
+    }
+
+    fn no_source_filter() -> SourceFilterArgs {
+        SourceFilterArgs {
+            exclude: Vec::new(),
+            respect_gitignore: false,
+        }
+    }
+
+    #[test]
+    fn test_collect_sources_exclude_and_gitignore() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().join("repo");
+        fs::create_dir_all(root.join("src"))?;
+        fs::create_dir_all(root.join("target/debug"))?;
+        fs::create_dir_all(root.join(".git"))?;
+        fs::create_dir_all(root.join("node_modules/pkg"))?;
+        fs::write(root.join(".gitignore"), "target/\n")?;
+        fs::write(root.join("src/main.rs"), "fn main() {}")?;
+        fs::write(root.join("src/debug.log"), "log")?;
+        fs::write(root.join("target/debug/app"), "bin")?;
+        fs::write(root.join(".git/HEAD"), "ref")?;
+        fs::write(root.join("node_modules/pkg/index.js"), "js")?;
+
+        let names = |filter: &SourceFilterArgs| -> anyhow::Result<Vec<String>> {
+            Ok(collect_sources(&[root.display().to_string()], filter)?
+                .into_iter()
+                .map(|s| s.name)
+                .collect())
+        };
+
+        let all = names(&no_source_filter())?;
+        assert!(all.contains(&"repo/target/debug/app".to_string()));
+        assert!(all.contains(&"repo/.git/HEAD".to_string()));
+
+        let filtered = names(&SourceFilterArgs {
+            exclude: vec!["node_modules".to_string(), "*.log".to_string()],
+            respect_gitignore: true,
+        })?;
+        assert_eq!(
+            filtered,
+            vec!["repo", "repo/.gitignore", "repo/src", "repo/src/main.rs"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_tar_gz_recurses_directories() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let src = dir.path().join("project");
+        fs::create_dir_all(src.join("nested/empty"))?;
+        fs::write(src.join("nested/a.txt"), "a")?;
+        fs::write(src.join("skip.tmp"), "tmp")?;
+
+        let archive = dir.path().join("project.tar.gz");
+        compress(&CompressArgs {
+            files: vec![src.display().to_string()],
+            output_compress: archive.display().to_string(),
+            level: None,
+            method: None,
+            source_filter: SourceFilterArgs {
+                exclude: vec!["*.tmp".to_string()],
+                respect_gitignore: false,
+            },
+        })?;
+
+        let out = dir.path().join("out");
+        decompress(&decompress_args(&archive, &out), &journal)?;
+        assert_eq!(fs::read_to_string(out.join("project/nested/a.txt"))?, "a");
+        assert!(out.join("project/nested/empty").is_dir());
+        assert!(!out.join("project/skip.tmp").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_from_name() {
+        assert_eq!(format_from_name("a.zip"), Some(Format::Zip));
+        assert_eq!(format_from_name("a.TAR.GZ"), Some(Format::Tar(Codec::Gzip)));
+        assert_eq!(format_from_name("a.tzst"), Some(Format::Tar(Codec::Zstd)));
+        assert_eq!(
+            format_from_name("a.log.xz"),
+            Some(Format::Stream(Codec::Xz))
+        );
+        assert_eq!(format_from_name("a.txt"), None);
+    }
+
+    #[test]
+    fn test_compress_decompress_all_tar_codecs() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("sub"))?;
+        fs::write(src.join("sub/a.txt"), "tar payload")?;
+
+        for (name, codec) in [
+            ("a.tar", Codec::None),
+            ("a.tar.gz", Codec::Gzip),
+            ("a.tar.bz2", Codec::Bzip2),
+            ("a.tar.xz", Codec::Xz),
+            ("a.tar.zst", Codec::Zstd),
+        ] {
+            let archive = dir.path().join(name);
+            compress(&CompressArgs {
+                files: vec![src.display().to_string()],
+                output_compress: archive.display().to_string(),
+                level: None,
+                method: None,
+                source_filter: no_source_filter(),
+            })?;
+            // Detection ignores the name, so a misleading extension still works.
+            let renamed = dir.path().join(format!("{}.bin", name));
+            fs::rename(&archive, &renamed)?;
+            assert_eq!(detect_format(&renamed)?, Format::Tar(codec));
+
+            let out = dir.path().join(format!("{}_out", name));
+            decompress(&decompress_args(&renamed, &out), &journal)?;
+            assert_eq!(
+                fs::read_to_string(out.join("src/sub/a.txt"))?,
+                "tar payload"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_decompress_single_file_streams() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let src = dir.path().join("app.log");
+        fs::write(&src, "line\n".repeat(50))?;
+
+        for ext in ["gz", "bz2", "xz", "zst"] {
+            let compressed = dir.path().join(format!("app.log.{}", ext));
+            compress(&CompressArgs {
+                files: vec![src.display().to_string()],
+                output_compress: compressed.display().to_string(),
+                level: Some(5),
+                method: None,
+                source_filter: no_source_filter(),
+            })?;
+
+            let out = dir.path().join(ext);
+            decompress(&decompress_args(&compressed, &out), &journal)?;
+            assert_eq!(
+                fs::read_to_string(out.join("app.log"))?,
+                "line\n".repeat(50)
+            );
+        }
+
+        let two_files = CompressArgs {
+            files: vec![src.display().to_string(), src.display().to_string()],
+            output_compress: dir.path().join("both.gz").display().to_string(),
+            level: None,
+            method: None,
+            source_filter: no_source_filter(),
+        };
+        assert!(compress(&two_files).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sniff_file_kinds() {
+        assert_eq!(sniff(b"", true), FileKind::Empty);
+        assert_eq!(sniff(b"PK\x03\x04rest", true), FileKind::Zip);
+        assert_eq!(
+            sniff(&[0x1f, 0x8b, 8, 0], true),
+            FileKind::Compressed(Codec::Gzip)
+        );
+        assert_eq!(
+            sniff(b"BZh91AY&SY", true),
+            FileKind::Compressed(Codec::Bzip2)
+        );
+        assert_eq!(sniff(b"BZh is not bzip2", true), FileKind::Text);
+        assert_eq!(sniff(b"{\"a\": [1, 2]}", true), FileKind::Json);
+        assert_eq!(sniff(b"[1, 2, 3", false), FileKind::Json);
+        assert_eq!(sniff(b"[1, 2, 3", true), FileKind::Text);
+        assert_eq!(sniff(b"a;b\n1;\"x;y\"\n", true), FileKind::Csv(b';'));
+        assert_eq!(sniff(b"a\tb\n1\t2\n", true), FileKind::Tsv);
+        assert_eq!(sniff(b"\xff\xfeh\0i\0", true), FileKind::Utf16Text);
+        assert_eq!(sniff(b"h\0e\0l\0l\0o\0", true), FileKind::Utf16Text);
+        assert_eq!(sniff(b"hello\nworld\n", true), FileKind::Text);
+        assert_eq!(sniff(b"caf\xc3", false), FileKind::Text);
+        assert_eq!(sniff(b"caf\xc3", true), FileKind::Binary);
+        assert_eq!(
+            sniff(&[0x7f, b'E', b'L', b'F', 0, 1], true),
+            FileKind::Binary
+        );
+
+        // A zero block whose checksum field matches its own sum is still not a header.
+        let mut block = [0u8; 512];
+        block[148..156].copy_from_slice(b"000400\0 ");
+        assert!(!is_tar_header(&block));
+        block[0] = b'a';
+        block[148..156].copy_from_slice(b"000541\0 ");
+        assert!(is_tar_header(&block));
+    }
+
+    #[test]
+    fn test_identify_gzipped_json_is_rejected() -> anyhow::Result<()> {
+        use std::io::Write;
+
+        let dir = tempdir()?;
+        let json_path = dir.path().join("data.json");
+        let mut encoder = flate2::write::GzEncoder::new(
+            fs::File::create(&json_path)?,
+            flate2::Compression::default(),
+        );
+        encoder.write_all(br#"[{"a": 1}]"#)?;
+        encoder.finish()?;
+
+        let identity = identify(&json_path)?;
+        assert_eq!(identity.kind, FileKind::Compressed(Codec::Gzip));
+        assert_eq!(identity.inner, Some(FileKind::Json));
+
+        let args = JsonToCsvArgs {
+            json_path: json_path.clone(),
+            output_csv_path: dir.path().join("out.csv"),
+        };
+        let err = json_to_csv(&args).unwrap_err().to_string();
+        assert!(err.contains("gzip compressed data"), "{}", err);
+        assert!(!dir.path().join("out.csv").exists());
+
+        // Content decides, so JSON under another name converts fine.
+        let plain = dir.path().join("data.txt");
+        fs::write(&plain, r#"[{"a": 1, "b": "x"}]"#)?;
+        json_to_csv(&JsonToCsvArgs {
+            json_path: plain,
+            output_csv_path: dir.path().join("out.csv"),
+        })?;
+        assert!(dir.path().join("out.csv").exists());
+        Ok(())
+    }
+
+    fn search_args(pattern: &str) -> SearchArgs {
+        SearchArgs {
+            inputs: vec![pattern.to_string()],
+            regexp: Vec::new(),
+            patterns_file: None,
+            fixed_strings: false,
+            word_regexp: false,
+            line_regexp: false,
+            case_insensitive: false,
+            hidden: false,
+            color: false,
+            glob: Vec::new(),
+            iglob: Vec::new(),
+            file_type: Vec::new(),
+            respect_gitignore: false,
+            after_context: None,
+            before_context: None,
+            context: None,
+            max_count: None,
+            files_with_matches: false,
+            files_without_match: false,
+            count: false,
+            invert_match: false,
+            multiline: false,
+            multiline_limit: 64 << 20,
+            json: false,
+            search_zip: false,
+            search_zip_limit: 1 << 30,
+            binary: BinaryMode::Skip,
+            parallel: ParallelArgs {
+                threads: None,
+                sort: false,
+            },
+        }
+    }
+
+    /// Runs `search_reader` over `text` as if it were the file `f.txt`.
+    fn search_text(text: &str, args: &SearchArgs) -> anyhow::Result<String> {
+        let matcher = Matcher::from_args(args)?;
+        let mut out = termcolor::Buffer::no_color();
+        search_reader("f.txt", text.as_bytes(), &matcher, args, &mut out)?;
+        Ok(String::from_utf8(out.into_inner())?)
+    }
+
+    #[test]
+    fn test_search_context_groups() -> anyhow::Result<()> {
+        let text = "a\nhit 1\nb\nc\nd\ne\nhit 2\nf\nhit 3\ng\n";
+
+        let mut args = search_args("hit");
+        args.context = Some(1);
+        assert_eq!(
+            search_text(text, &args)?,
+            "f.txt-1- a\nf.txt:2: hit 1\nf.txt-3- b\n--\n\
+             f.txt-6- e\nf.txt:7: hit 2\nf.txt-8- f\nf.txt:9: hit 3\nf.txt-10- g\n"
+        );
+
+        let mut args = search_args("hit");
+        args.before_context = Some(2);
+        args.max_count = Some(1);
+        assert_eq!(search_text(text, &args)?, "f.txt-1- a\nf.txt:2: hit 1\n");
+
+        // Trailing context is still printed once the limit is reached.
+        args.after_context = Some(1);
+        assert_eq!(
+            search_text(text, &args)?,
+            "f.txt-1- a\nf.txt:2: hit 1\nf.txt-3- b\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_invert_count_and_file_names() -> anyhow::Result<()> {
+        let text = "one\nhit\ntwo\nhit again\n";
+
+        let mut args = search_args("hit");
+        args.invert_match = true;
+        assert_eq!(search_text(text, &args)?, "f.txt:1: one\nf.txt:3: two\n");
+
+        let mut args = search_args("hit");
+        args.count = true;
+        assert_eq!(search_text(text, &args)?, "f.txt:2\n");
+        args.max_count = Some(1);
+        assert_eq!(search_text(text, &args)?, "f.txt:1\n");
+
+        let mut args = search_args("hit");
+        args.files_with_matches = true;
+        assert_eq!(search_text(text, &args)?, "f.txt\n");
+
+        let mut args = search_args("missing");
+        args.files_with_matches = true;
+        assert_eq!(search_text(text, &args)?, "");
+        args.files_with_matches = false;
+        args.files_without_match = true;
+        assert_eq!(search_text(text, &args)?, "f.txt\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_files_parallel_sorted() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let mut expected = Vec::new();
+        for i in 0..40 {
+            let sub = dir.path().join(format!("d{}", i % 4));
+            fs::create_dir_all(&sub)?;
+            let file = sub.join(format!("f{:02}.txt", i));
+            fs::write(&file, "x\n".repeat(i))?;
+            expected.push(file);
+        }
+        expected.sort();
+
+        let parallel = ParallelArgs {
+            threads: Some(4),
+            sort: true,
+        };
+        let mut seen = Vec::new();
+        walk_files_parallel(
+            &mut ignore::WalkBuilder::new(dir.path()),
+            &parallel,
+            |path| Ok(Some(fs::read_to_string(path)?.lines().count())),
+            |path, lines| {
+                seen.push((path.to_path_buf(), lines));
+                Ok(())
+            },
+        )?;
+
+        let paths: Vec<_> = seen.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(paths, expected);
+        assert_eq!(seen.iter().map(|(_, lines)| lines).sum::<usize>(), 780);
+
+        // An error from one file stops the walk and is returned.
+        let result = walk_files_parallel(
+            &mut ignore::WalkBuilder::new(dir.path()),
+            &parallel,
+            |path| -> anyhow::Result<Option<()>> {
+                if path.ends_with("f07.txt") {
+                    anyhow::bail!("boom");
+                }
+                Ok(None)
+            },
+            |_, _| Ok(()),
+        );
+        assert_eq!(result.unwrap_err().to_string(), "boom");
+
+        // A link named directly is followed; one met while walking is not.
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("d0").join("link.txt");
+            std::os::unix::fs::symlink(&expected[0], &link)?;
+            let mut builder = ignore::WalkBuilder::new(&link);
+            builder.add(dir.path().join("d0"));
+            let mut seen = Vec::new();
+            walk_files_parallel(
+                &mut builder,
+                &parallel,
+                |_| Ok(Some(())),
+                |path, _| {
+                    seen.push(path.to_path_buf());
+                    Ok(())
+                },
+            )?;
+            assert_eq!(seen.iter().filter(|path| **path == link).count(), 1);
+            assert_eq!(seen.len(), 11);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_binary_modes() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let text = dir.path().join("a.txt");
+        let binary = dir.path().join("b.bin");
+        fs::write(&text, "hello world")?;
+        fs::write(&binary, b"hello\0\xff\xfe world")?;
+
+        assert!(!is_binary(b"plain text\n"));
+        assert!(is_binary(b"hello\0world"));
+        assert!(is_binary(&[0x1f, 0x8b, 8, 0]));
+
+        let mut args = ReplaceArgs {
+            path: dir.path().to_path_buf(),
+            old_string: "hello".to_string(),
+            new_string: "hi".to_string(),
+            regex: false,
+            case_insensitive: false,
+            max_replacements: None,
+            lines: None,
+            dry_run: false,
+            interactive: false,
+            backup: false,
+            binary: BinaryMode::Error,
+            parallel: ParallelArgs {
+                threads: Some(1),
+                sort: true,
+            },
+        };
+        assert!(run_replace(&args, &journal)
+            .unwrap_err()
+            .to_string()
+            .contains("binary file"));
+        // Nothing is written, even the text file that comes first.
+        assert_eq!(fs::read_to_string(&text)?, "hello world");
+
+        // Skipping leaves the binary file alone and still handles the rest.
+        args.binary = BinaryMode::Skip;
+        fs::write(&text, "hello world")?;
+        run_replace(&args, &journal)?;
+        assert_eq!(fs::read_to_string(&text)?, "hi world");
+        assert_eq!(fs::read(&binary)?, b"hello\0\xff\xfe world");
+
+        // As text, only the matched bytes change.
+        args.binary = BinaryMode::Text;
+        run_replace(&args, &journal)?;
+        assert_eq!(fs::read(&binary)?, b"hi\0\xff\xfe world");
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_regex_captures_and_limits() -> anyhow::Result<()> {
+        let replace = |old: &str, new: &str, set: &dyn Fn(&mut ReplaceArgs), text: &str| {
+            let mut args = ReplaceArgs {
+                path: ".".into(),
+                old_string: old.to_string(),
+                new_string: new.to_string(),
+                regex: true,
+                case_insensitive: false,
+                max_replacements: None,
+                lines: None,
+                dry_run: false,
+                interactive: false,
+                backup: false,
+                binary: BinaryMode::Skip,
+                parallel: ParallelArgs {
+                    threads: None,
+                    sort: false,
+                },
+            };
+            set(&mut args);
+            let edits = Replacer::from_args(&args)?.find(text.as_bytes());
+            let out = apply_edits(text.as_bytes(), &edits);
+            anyhow::Ok((String::from_utf8(out)?, edits.len()))
+        };
+
+        assert_eq!(
+            replace(
+                r#"^version = "(\d+)\.(\d+)\.\d+""#,
+                r#"version = "$1.${2}.0""#,
+                &|_| {},
+                "name = \"x\"\nversion = \"1.2.3\"\n",
+            )?,
+            ("name = \"x\"\nversion = \"1.2.0\"\n".to_string(), 1)
+        );
+        assert_eq!(
+            replace(
+                r"(?P<d>\d{2})/(?P<m>\d{2})/(?P<y>\d{4})",
+                "$y-$m-$d",
+                &|_| {},
+                "01/02/2024 and 31/12/1999",
+            )?,
+            ("2024-02-01 and 1999-12-31".to_string(), 2)
+        );
+
+        // Without --regex both sides are literal.
+        assert_eq!(
+            replace("a.c", "$1", &|args| args.regex = false, "abc a.c")?,
+            ("abc $1".to_string(), 1)
+        );
+
+        let text = "Foo\nfoo\nFOO\nfoo\nfoo\n";
+        assert_eq!(
+            replace("foo", "x", &|args| args.case_insensitive = true, text)?,
+            ("x\nx\nx\nx\nx\n".to_string(), 5)
+        );
+        assert_eq!(
+            replace("foo", "x", &|args| args.max_replacements = Some(2), text)?,
+            ("Foo\nx\nFOO\nx\nfoo\n".to_string(), 2)
+        );
+        assert_eq!(
+            replace(
+                "foo",
+                "x",
+                &|args| args.lines = Some(parse_line_range("2:4").unwrap()),
+                text
+            )?,
+            ("Foo\nx\nFOO\nx\nfoo\n".to_string(), 2)
+        );
+        assert_eq!(
+            replace(
+                "foo",
+                "x",
+                &|args| args.lines = Some(parse_line_range("4:").unwrap()),
+                text
+            )?,
+            ("Foo\nfoo\nFOO\nx\nx\n".to_string(), 2)
+        );
+        // A match running past the last line of the range is left alone.
+        assert_eq!(
+            replace(
+                "1\n2",
+                "x",
+                &|args| args.lines = Some(parse_line_range("1").unwrap()),
+                "1\n2\n"
+            )?,
+            ("1\n2\n".to_string(), 0)
+        );
+        // Nor does one starting before it take text from the range.
+        assert_eq!(
+            replace(
+                "a\na|ac",
+                "x",
+                &|args| args.lines = Some(parse_line_range("2").unwrap()),
+                "xa\nac\n"
+            )?,
+            ("xa\nx\n".to_string(), 1)
+        );
+        // `$` ends the last line even without a line break after it, but
+        // starts no extra line after one.
+        assert_eq!(
+            replace("$", ";", &|_| {}, "a\nb")?,
+            ("a;\nb;".to_string(), 2)
+        );
+        assert_eq!(
+            replace("$", ";", &|_| {}, "a\nb\n")?,
+            ("a;\nb;\n".to_string(), 2)
+        );
+        assert_eq!(
+            replace(
+                "$",
+                ";",
+                &|args| args.lines = Some(parse_line_range("2:").unwrap()),
+                "a\nb"
+            )?,
+            ("a\nb;".to_string(), 1)
+        );
+        // `$` matches before a CRLF line ending too.
+        assert_eq!(
+            replace("o$", "0", &|_| {}, "foo\r\nboo\r\n")?,
+            ("fo0\r\nbo0\r\n".to_string(), 2)
+        );
+
+        assert_eq!(
+            parse_line_range(":200")?,
+            LineRange {
+                start: 1,
+                end: Some(200)
+            }
+        );
+        assert!(parse_line_range("0:5").is_err());
+        assert!(parse_line_range("9:5").is_err());
+        assert!(parse_line_range("a:b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_dry_run_diff_and_review() -> anyhow::Result<()> {
+        let journal = test_journal()?;
+        let dir = tempdir()?;
+        let file = dir.path().join("notes.txt");
+        let text: String = (1..=12).map(|i| format!("line {}\n", i)).collect();
+        let text = text
+            .replace("line 2\n", "old 2\n")
+            .replace("line 11\n", "old 11\n");
+        fs::write(&file, &text)?;
+
+        let mut args = ReplaceArgs {
+            path: dir.path().to_path_buf(),
+            old_string: "old".to_string(),
+            new_string: "new".to_string(),
+            regex: false,
+            case_insensitive: false,
+            max_replacements: None,
+            lines: None,
+            dry_run: true,
+            interactive: false,
+            backup: true,
+            binary: BinaryMode::Skip,
+            parallel: ParallelArgs {
+                threads: Some(1),
+                sort: true,
+            },
+        };
+
+        // A dry run touches nothing, backups included.
+        run_replace(&args, &journal)?;
+        assert_eq!(fs::read_to_string(&file)?, text);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        // Changes more than 2 * 3 lines apart get their own hunk.
+        args.regex = true;
+        args.old_string = "old (\\d+)\n".to_string();
+        args.new_string = "new $1\nadded\n".to_string();
+        let edits = Replacer::from_args(&args)?.find(text.as_bytes());
+        let hunks = diff_hunks(text.as_bytes(), &edits, 3);
+        assert_eq!(hunks.len(), 2);
+        assert_eq!(
+            (
+                hunks[0].old_start,
+                hunks[0].old_len,
+                hunks[0].new_start,
+                hunks[0].new_len
+            ),
+            (1, 5, 1, 6)
+        );
+        let lines: Vec<String> = hunks[0]
+            .lines
+            .iter()
+            .map(|(marker, text)| format!("{}{}", marker, text))
+            .collect();
+        assert_eq!(
+            lines,
+            [" line 1", "-old 2", "+new 2", "+added", " line 3", " line 4", " line 5"]
+        );
+        assert_eq!(
+            (
+                hunks[1].old_start,
+                hunks[1].old_len,
+                hunks[1].new_start,
+                hunks[1].new_len
+            ),
+            (8, 5, 9, 6)
+        );
+        assert_eq!(hunks[1].edits, 1..2);
+
+        // Changes close together share a hunk.
+        let close = "a x\nb\nc x\n";
+        let edits = Replacer::from_args(&ReplaceArgs {
+            old_string: "x".to_string(),
+            new_string: "y".to_string(),
+            regex: false,
+            ..args
+        })?
+        .find(close.as_bytes());
+        let hunks = diff_hunks(close.as_bytes(), &edits, 3);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(hunks[0].edits, 0..2);
+
+        let mut review = Review::default();
+        let mut answers = std::io::Cursor::new("maybe\nn\ny\na\n");
+        assert!(!review.accepts(&mut answers)?);
+        assert!(review.accepts(&mut answers)?);
+        assert!(review.accepts(&mut answers)?);
+        assert!(review.all);
+        assert!(review.accepts(&mut answers)?);
+
+        let mut review = Review::default();
+        assert!(!review.accepts(&mut std::io::Cursor::new("q\n"))?);
+        assert!(review.quit);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_keeps_mode_and_links() -> anyhow::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir()?;
+        let file = dir.path().join("run.sh");
+        fs::write(&file, "old")?;
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o750))?;
+        let link = dir.path().join("link.sh");
+        std::os::unix::fs::symlink(&file, &link)?;
+
+        write_atomic(&link, |f| Ok(f.write_all(b"new")?))?;
+        assert_eq!(fs::read_to_string(&file)?, "new");
+        assert_eq!(fs::metadata(&file)?.permissions().mode() & 0o777, 0o750);
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+
+        // A failed write leaves the file as it was, and no temp file behind.
+        let result = write_atomic(&file, |f| {
+            f.write_all(b"partial")?;
+            anyhow::bail!("disk full")
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&file)?, "new");
+        assert_eq!(fs::read_dir(dir.path())?.count(), 2);
+
+        // A new file gets 0o666 less the umask, like `File::create` gives it.
+        let reference = dir.path().join("reference.txt");
+        fs::write(&reference, "")?;
+        let umask = !fs::metadata(&reference)?.permissions().mode() & 0o666;
+        let created = dir.path().join("created.txt");
+        write_atomic(&created, |f| Ok(f.write_all(b"x")?))?;
+        assert_eq!(fs::read_to_string(&created)?, "x");
+        assert_eq!(
+            fs::metadata(&created)?.permissions().mode() & 0o777,
+            0o666 & !umask
+        );
+
+        // Without following, the link itself is replaced.
+        write_atomic_no_follow(&link, |f| Ok(f.write_all(b"extracted")?))?;
+        assert!(fs::symlink_metadata(&link)?.file_type().is_file());
+        assert_eq!(fs::read_to_string(&link)?, "extracted");
+        assert_eq!(fs::read_to_string(&file)?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_zip_archives() -> anyhow::Result<()> {
+        use std::io::Write;
+        use std::sync::atomic::AtomicUsize;
+
+        let dir = tempdir()?;
+        let gz = dir.path().join("app.log.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(fs::File::create(&gz)?, flate2::Compression::default());
+        encoder.write_all(b"start\nERROR disk full\n")?;
+        encoder.finish()?;
+
+        let zip = dir.path().join("release.zip");
+        write_zip(
+            &zip,
+            &[("docs/", ""), ("docs/notes.txt", "ok\nERROR in notes\n")],
+        )?;
+        let tgz = dir.path().join("logs.tar.gz");
+        write_tar_gz(
+            &tgz,
+            &[("logs/", ""), ("logs/a.log", "ERROR first\nfine\n")],
+        )?;
+
+        let matcher = Matcher::from_args(&search_args("ERROR"))?;
+        let skipped = AtomicUsize::new(0);
+        let mut args = search_args("ERROR");
+        args.search_zip = true;
+
+        let search = |path: &std::path::Path, args: &SearchArgs| -> anyhow::Result<String> {
+            let mut out = termcolor::Buffer::no_color();
+            search_path(path, &matcher, args, &mut out, &skipped)?;
+            Ok(String::from_utf8(out.into_inner())?)
+        };
+
+        assert_eq!(
+            search(&gz, &args)?,
+            format!("{}:2: ERROR disk full\n", gz.display())
+        );
+        assert_eq!(
+            search(&zip, &args)?,
+            format!("{}!docs/notes.txt:2: ERROR in notes\n", zip.display())
+        );
+        assert_eq!(
+            search(&tgz, &args)?,
+            format!("{}!logs/a.log:1: ERROR first\n", tgz.display())
+        );
+
+        // A truncated file, or one that decompresses past the limit, is
+        // reported and skipped; it does not end the search.
+        let truncated = dir.path().join("cut.log.gz");
+        fs::write(&truncated, &fs::read(&gz)?[..10])?;
+        assert_eq!(search(&truncated, &args)?, "");
+        args.search_zip_limit = 8;
+        assert_eq!(search(&gz, &args)?, "");
+        assert_eq!(search(&zip, &args)?, "");
+
+        // Without the flag, compressed data is binary and left out.
+        args.search_zip = false;
+        assert_eq!(search(&gz, &args)?, "");
+        assert_eq!(skipped.load(std::sync::atomic::Ordering::Relaxed), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_json_output() -> anyhow::Result<()> {
+        let text = "skip\nfoo and foo\nafter\n";
+        let mut args = search_args("foo");
+        args.json = true;
+        args.after_context = Some(1);
+        let matcher = Matcher::from_args(&args)?;
+
+        let mut out = termcolor::Buffer::no_color();
+        let stats = search_reader("f.txt", text.as_bytes(), &matcher, &args, &mut out)?;
+        assert_eq!((stats.files, stats.bytes, stats.matches), (1, 23, 1));
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(out.into_inner())?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({
+                    "type": "match",
+                    "path": "f.txt",
+                    "line": 2,
+                    "end_line": 2,
+                    "text": "foo and foo",
+                    "submatches": [
+                        { "start": 0, "end": 3, "text": "foo", "pattern": 0 },
+                        { "start": 8, "end": 11, "text": "foo", "pattern": 0 },
+                    ],
+                    "patterns": [0],
+                }),
+                serde_json::json!({
+                    "type": "context",
+                    "path": "f.txt",
+                    "line": 3,
+                    "end_line": 3,
+                    "text": "after",
+                    "submatches": [],
+                    "patterns": [],
+                }),
+            ]
+        );
+
+        // Offsets index the decoded text, where the invalid byte takes three bytes.
+        let mut out = termcolor::Buffer::no_color();
+        search_reader("f.txt", &b"\xff foo\n"[..], &matcher, &args, &mut out)?;
+        let hit: serde_json::Value = serde_json::from_slice(out.as_slice())?;
+        assert_eq!(hit["text"], "\u{fffd} foo");
+        assert_eq!(hit["submatches"][0]["start"], 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_matcher_multiple_patterns() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let signatures = dir.path().join("signatures.txt");
+        fs::write(&signatures, "disk full\n\nOOM\n")?;
+
+        // With -e or -f, every positional is a path.
+        let mut args = search_args("src/");
+        args.regexp = vec!["timeout".to_string(), r"err\d+".to_string()];
+        assert_eq!(args.pattern(), None);
+        assert_eq!(args.paths(), ["src/"]);
+        args.patterns_file = Some(signatures);
+        let matcher = Matcher::from_args(&args)?;
+        assert_eq!(matcher.pattern_count(), 4);
+        assert_eq!(
+            matcher.find_iter("err42: disk full after timeout"),
+            vec![
+                Span {
+                    start: 0,
+                    end: 5,
+                    pattern: 1
+                },
+                Span {
+                    start: 7,
+                    end: 16,
+                    pattern: 2
+                },
+                Span {
+                    start: 23,
+                    end: 30,
+                    pattern: 0
+                },
+            ]
+        );
+        assert_eq!(
+            search_text("ok\nOOM and timeout\n", &args)?,
+            "f.txt:2: [0,3] OOM and timeout\n"
+        );
+
+        // Literals: regex syntax is taken as is, overlapping ones all count.
+        let mut args = search_args("a.b");
+        args.inputs.clear();
+        args.regexp = vec!["a.b".to_string(), "a.b.c".to_string()];
+        args.fixed_strings = true;
+        args.case_insensitive = true;
+        let matcher = Matcher::from_args(&args)?;
+        assert!(!matcher.is_match("axb"));
+        assert_eq!(matcher.matching_patterns("x A.B.C"), vec![0, 1]);
+        assert_eq!(
+            matcher.find_iter("x A.B.C"),
+            vec![Span {
+                start: 2,
+                end: 7,
+                pattern: 1
+            }]
+        );
+
+        // Patterns may use the same group name.
+        let mut args = search_args("x");
+        args.inputs.clear();
+        args.regexp = vec![
+            "(?P<id>id)".to_string(),
+            r"(?<id>=)[(?P<x>]".to_string(),
+            r"\(?P<id>".to_string(),
+        ];
+        let matcher = Matcher::from_args(&args)?;
+        assert_eq!(
+            matcher.find_iter("id=( P<id>"),
+            vec![
+                Span {
+                    start: 0,
+                    end: 2,
+                    pattern: 0
+                },
+                Span {
+                    start: 2,
+                    end: 4,
+                    pattern: 1
+                },
+                Span {
+                    start: 5,
+                    end: 10,
+                    pattern: 2
+                },
+            ]
+        );
+
+        let mut args = search_args("cat");
+        args.word_regexp = true;
+        assert!(Matcher::from_args(&args)?.is_match("a cat."));
+        assert!(!Matcher::from_args(&args)?.is_match("concat"));
+        args.word_regexp = false;
+        args.line_regexp = true;
+        args.fixed_strings = true;
+        assert!(Matcher::from_args(&args)?.is_match("cat"));
+        assert!(!Matcher::from_args(&args)?.is_match("cat food"));
+
+        let mut args = search_args("x");
+        args.inputs.clear();
+        assert!(Matcher::from_args(&args).is_err());
+
+        // Only the invalid pattern is named, with its line in the file.
+        fs::write(dir.path().join("bad.txt"), "ok\n\n(unclosed\nfine\n")?;
+        args.patterns_file = Some(dir.path().join("bad.txt"));
+        let error = format!("{:#}", Matcher::from_args(&args).err().unwrap());
+        assert!(error.contains("'(unclosed' (line 3 of"), "{}", error);
+        assert!(!error.contains("fine"), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_multiline() -> anyhow::Result<()> {
+        use std::sync::atomic::AtomicUsize;
+
+        let dir = tempdir()?;
+        let log = dir.path().join("app.log");
+        fs::write(
+            &log,
+            "start\r\nException in thread main\r\n  at Foo.bar\r\nCaused by: IOException\r\nnext\r\nend\r\n",
+        )?;
+
+        let mut args = search_args(r"Exception in.*?\nCaused by:");
+        let skipped = AtomicUsize::new(0);
+        let search = |args: &SearchArgs| -> anyhow::Result<String> {
+            let matcher = Matcher::from_args(args)?;
+            let mut out = termcolor::Buffer::no_color();
+            search_path(&log, &matcher, args, &mut out, &skipped)?;
+            Ok(String::from_utf8(out.into_inner())?)
+        };
+
+        assert_eq!(search(&args)?, "");
+
+        args.multiline = true;
+        args.after_context = Some(1);
+        let path = log.display();
+        assert_eq!(
+            search(&args)?,
+            format!(
+                "{p}:2: Exception in thread main\n{p}:3:   at Foo.bar\n\
+                 {p}:4: Caused by: IOException\n{p}-5- next\n",
+                p = path
+            )
+        );
+
+        args.after_context = None;
+        args.json = true;
+        let object: serde_json::Value = serde_json::from_str(search(&args)?.trim())?;
+        assert_eq!(object["line"], 2);
+        assert_eq!(object["end_line"], 4);
+        assert_eq!(object["submatches"][0]["start"], 0);
+        assert_eq!(object["submatches"][0]["end"], 50);
+
+        args.multiline_limit = 16;
+        assert_eq!(search(&args)?, "");
+
+        // A match ending on the last line.
+        let tail = dir.path().join("tail.txt");
+        fs::write(&tail, "x\nfoo\nbar\n")?;
+        let mut args = search_args(r"foo\nbar");
+        args.multiline = true;
+        let mut out = termcolor::Buffer::no_color();
+        search_path(
+            &tail,
+            &Matcher::from_args(&args)?,
+            &args,
+            &mut out,
+            &skipped,
+        )?;
+        assert_eq!(
+            String::from_utf8(out.into_inner())?,
+            format!("{p}:2: foo\n{p}:3: bar\n", p = tail.display())
+        );
+
+        assert_eq!(parse_size("512")?, 512);
+        assert_eq!(parse_size("64K")?, 64 << 10);
+        assert_eq!(parse_size("2mb")?, 2 << 20);
+        assert!(parse_size("5T").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_walker_selects_files() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        fs::create_dir_all(root.join("src"))?;
+        fs::create_dir_all(root.join("logs"))?;
+        for file in [
+            "src/main.rs",
+            "src/notes.txt",
+            "logs/app.log",
+            "logs/app.LOG",
+            "logs/old.log.gz",
+            "logs/skip.log",
+            "data.csv",
+        ] {
+            fs::write(root.join(file), "x\n")?;
+        }
+        fs::write(root.join(".gitignore"), "skip.log\n")?;
+
+        let found = |args: &SearchArgs| -> anyhow::Result<Vec<String>> {
+            let mut names: Vec<String> = search_walker(args)?
+                .build()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+                .map(|entry| {
+                    let path = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                    path.to_string_lossy().replace('\\', "/")
+                })
+                .collect();
+            names.sort();
+            Ok(names)
+        };
+        let with_inputs = |inputs: &[&str]| {
+            let mut args = search_args("x");
+            args.inputs = inputs
+                .iter()
+                .map(|input| root.join(input).to_string_lossy().into_owned())
+                .chain(["x".to_string()])
+                .collect();
+            args
+        };
+
+        // Several paths, and a glob expanded like the shell would.
+        let args = with_inputs(&["src/main.rs", "logs/*.log", "data.csv"]);
+        assert_eq!(
+            found(&args)?,
+            ["data.csv", "logs/app.log", "logs/skip.log", "src/main.rs"]
+        );
+
+        let mut args = with_inputs(&[""]);
+        args.file_type = vec!["rust".to_string(), "csv".to_string()];
+        assert_eq!(found(&args)?, ["data.csv", "src/main.rs"]);
+
+        let mut args = with_inputs(&["logs"]);
+        args.glob = vec!["*.log".to_string(), "!skip*".to_string()];
+        assert_eq!(found(&args)?, ["logs/app.log"]);
+        args.search_zip = true;
+        assert_eq!(found(&args)?, ["logs/app.log", "logs/old.log.gz"]);
+
+        let mut args = with_inputs(&["logs"]);
+        args.iglob = vec!["*.log".to_string()];
+        assert_eq!(
+            found(&args)?,
+            ["logs/app.LOG", "logs/app.log", "logs/skip.log"]
+        );
+
+        let mut args = with_inputs(&["logs"]);
+        args.respect_gitignore = true;
+        assert_eq!(
+            found(&args)?,
+            ["logs/app.LOG", "logs/app.log", "logs/old.log.gz"]
+        );
+
+        args.file_type = vec!["nonsense".to_string()];
+        assert!(search_walker(&args).is_err());
+        assert!(search_walker(&with_inputs(&["missing.txt"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_undo() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let journal = Journal::new(
+            dir.path().join("journal"),
+            Trash::new(dir.path().join("trash")),
+        );
+        let work = dir.path().join("work");
+        fs::create_dir(&work)?;
+        let edited = work.join("edited.txt");
+        fs::write(&edited, "before")?;
+        let moved = work.join("moved.txt");
+        fs::write(&moved, "moved")?;
+
+        // Nothing is recorded until something changes.
+        let op = journal.begin("noop");
+        drop(op);
+        assert!(journal.list()?.is_empty());
+
+        let op = journal.begin("replace");
+        op.write(&edited, || Ok(fs::write(&edited, "after")?))?;
+        op.create_dir_all(&work.join("a/b"))?;
+        let created = work.join("a/b/new.txt");
+        op.write(&created, || Ok(fs::write(&created, "new")?))?;
+        let renamed = work.join("renamed.txt");
+        op.rename(&moved, &renamed, || Ok(fs::rename(&moved, &renamed)?))?;
+        drop(op);
+
+        let records = journal.list()?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].command, "replace");
+        assert_eq!(records[0].changes.len(), 5);
+        assert!(matches!(records[0].changes[0], Change::Modified { .. }));
+        let id = records[0].id.clone();
+
+        let op = journal.begin("touch");
+        op.write(&created, || Ok(fs::write(&created, "newer")?))?;
+        drop(op);
+        let records = journal.list()?;
+        assert_eq!(records.len(), 2);
+
+        // A file changed since blocks the undo and nothing is touched.
+        let err = journal.undo(&id).unwrap_err().to_string();
+        assert!(err.contains("changed since"), "{}", err);
+        assert_eq!(fs::read_to_string(&edited)?, "after");
+
+        // Undoing the later operation first clears the way.
+        journal.undo(&records[1].id)?;
+        journal.undo(&id)?;
+
+        assert_eq!(fs::read_to_string(&edited)?, "before");
+        assert_eq!(fs::read_to_string(&moved)?, "moved");
+        assert!(!renamed.exists());
+        assert!(!work.join("a").exists());
+        assert!(journal.list()?.iter().all(|r| r.undone_at.is_some()));
+        assert!(journal.undo(&id).is_err());
+        assert!(journal.undo("missing").is_err());
+        let err = journal.undo("../journal").unwrap_err().to_string();
+        assert!(err.contains("Invalid id"), "{}", err);
+
+        // A path written twice in one operation is one change, back to how
+        // it was before the first write.
+        let op = journal.begin("extract twice");
+        op.write(&edited, || Ok(fs::write(&edited, "first")?))?;
+        op.write(&edited, || Ok(fs::write(&edited, "second write")?))?;
+        let twice = work.join("twice.txt");
+        op.write(&twice, || Ok(fs::write(&twice, "first")?))?;
+        op.write(&twice, || Ok(fs::write(&twice, "second write")?))?;
+        drop(op);
+        let record = journal.list()?.pop().unwrap();
+        assert_eq!(record.changes.len(), 2);
+        journal.undo(&record.id)?;
+        assert_eq!(fs::read_to_string(&edited)?, "before");
+        assert!(!twice.exists());
+
+        let count = journal.list()?.len();
+        assert_eq!(journal.prune(Some(chrono::Duration::weeks(1)))?, 0);
+        assert_eq!(journal.prune(None)?, count);
+        assert!(journal.list()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_template() -> anyhow::Result<()> {
+        use chrono::{Local, TimeZone};
+
+        let context = NameContext {
+            counter: 7,
+            modified: Local.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap(),
+        };
+        let new_name = |from: &str, to: &str, name: &str| -> anyhow::Result<Option<String>> {
+            Ok(Renamer::new(from, to)?.new_name(name, &context))
+        };
+
+        assert_eq!(
+            new_name(
+                r"^report \((\d+)\)\.csv$",
+                "report-{1:03}.{ext}",
+                "report (3).csv"
+            )?,
+            Some("report-003.csv".to_string())
+        );
+        assert_eq!(new_name(r"report", "x", "notes.txt")?, None);
+        // Only the matched part is replaced.
+        assert_eq!(
+            new_name(r"IMG", "{date}_{n:04}", "IMG_1.JPG")?,
+            Some("2024-03-09_0007_1.JPG".to_string())
+        );
+        assert_eq!(
+            new_name(
+                r"^.*$",
+                "{date:%Y%m%d}-{stem:kebab}.{ext:lower}",
+                "MyHTMLFile v2.TXT"
+            )?,
+            Some("20240309-my-html-file-v2.txt".to_string())
+        );
+        assert_eq!(
+            new_name(r"^(?P<base>\w+)\.(\w+)$", "{base:upper}{{{2}}}", "draft.md")?,
+            Some("DRAFT{md}".to_string())
+        );
+        assert_eq!(
+            split_words("parseHTTPResponse_v2"),
+            ["parse", "http", "response", "v2"]
+        );
+
+        assert!(Renamer::new("x", "{2}").is_err());
+        assert!(Renamer::new("x", "{nope}").is_err());
+        assert!(Renamer::new("x", "{n:title}").is_err());
+        assert!(Renamer::new("x", "{date:%Q}").is_err());
+        assert!(Renamer::new("x", "{n").is_err());
+        assert!(Renamer::new("(", "x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_all_cycles_rollback_and_undo() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        for name in ["a", "b", "c"] {
+            fs::write(path(name), name)?;
+        }
+
+        // A name may pass to another file of the batch, but not to an outsider.
+        let cycle = vec![
+            (path("a"), path("b")),
+            (path("b"), path("c")),
+            (path("c"), path("a")),
+        ];
+        assert!(conflicts(&cycle).is_empty());
+        let taken = vec![(path("a"), path("b"))];
+        assert_eq!(conflicts(&taken).len(), 1);
+        let both = vec![(path("a"), path("x")), (path("b"), path("x"))];
+        assert_eq!(conflicts(&both).len(), 1);
+
+        let journal = Journal::new(path("journal"), Trash::new(path("trash")));
+        let op = journal.begin("rename");
+        op.rename_all(&cycle, || rename_all(&cycle))?;
+        drop(op);
+        assert_eq!(fs::read_to_string(path("b"))?, "a");
+        assert_eq!(fs::read_to_string(path("c"))?, "b");
+        assert_eq!(fs::read_to_string(path("a"))?, "c");
+
+        // The second move cannot be done, so the first is taken back.
+        let broken = vec![(path("a"), path("d")), (path("b"), path("missing/e"))];
+        assert!(rename_all(&broken).is_err());
+        assert_eq!(fs::read_to_string(path("a"))?, "c");
+        assert_eq!(fs::read_to_string(path("b"))?, "a");
+        assert!(!path("d").exists());
+
+        journal.undo(&journal.list()?[0].id)?;
+        for name in ["a", "b", "c"] {
+            assert_eq!(fs::read_to_string(path(name))?, name);
+        }
+        assert_eq!(fs::read_dir(dir.path())?.count(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_edited_names() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let path = |name: &str| dir.path().join(name);
+        fs::write(path("a"), "a")?;
+        fs::write(path("b"), "b")?;
+        fs::create_dir(path("sub"))?;
+
+        let names = entry_names(dir.path())?;
+        assert_eq!(names, ["a", "b", "sub"]);
+
+        let moves = edited_moves(dir.path(), &names, "b\r\na\nsub\n")?;
+        assert_eq!(moves, [(path("a"), path("b")), (path("b"), path("a"))]);
+        assert!(conflicts(&moves).is_empty());
+        rename_all(&moves)?;
+        assert_eq!(fs::read_to_string(path("a"))?, "b");
+        assert_eq!(fs::read_to_string(path("b"))?, "a");
+
+        assert!(edited_moves(dir.path(), &names, "a\nb\n").is_err());
+        assert!(edited_moves(dir.path(), &names, "a\n\nsub/x\n").is_err());
+        // Giving a name that is kept by another entry is caught before renaming.
+        let moves = edited_moves(dir.path(), &names, "sub\nb\nsub\n")?;
+        assert_eq!(conflicts(&moves).len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use crate::command::{TrashArgs, TrashCommands};
+use crate::file_handler::move_by_copy;
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Duration, Local};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+const TRASH_DIR_ENV: &str = "CLAP_TOOL_FILE_TRASH";
+
+/// Per-user trash bin. Every trashed item lives in `files/<id>` and has a
+/// matching `info/<id>.json` describing where it came from.
+pub struct Trash {
+    root: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct TrashEntry {
+    pub id: String,
+    pub original_path: PathBuf,
+    pub deleted_at: DateTime<Local>,
+}
+
+impl Trash {
+    pub fn new(root: PathBuf) -> Self {
+        Trash { root }
+    }
+
+    /// Opens the trash of the current user, `$CLAP_TOOL_FILE_TRASH` if set,
+    /// otherwise `trash` in the [`data_dir`].
+    pub fn open_default() -> anyhow::Result<Self> {
+        if let Some(dir) = std::env::var_os(TRASH_DIR_ENV) {
+            return Ok(Trash::new(PathBuf::from(dir)));
+        }
+
+        Ok(Trash::new(data_dir()?.join("trash")))
+    }
+
+    fn files_dir(&self) -> PathBuf {
+        self.root.join("files")
+    }
+
+    fn info_dir(&self) -> PathBuf {
+        self.root.join("info")
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.info_dir().join(format!("{}.json", id))
+    }
+
+    /// Moves `path` into the trash and returns the id it was stored under.
+    pub fn put(&self, path: &Path) -> anyhow::Result<String> {
+        if fs::symlink_metadata(path).is_err() {
+            return Err(anyhow!("Path '{}' does not exist.", path.display()));
+        }
+
+        fs::create_dir_all(self.files_dir())?;
+        fs::create_dir_all(self.info_dir())?;
+
+        let original_path = std::path::absolute(path)?;
+        let deleted_at = Local::now();
+        let id = self.next_id(&deleted_at);
+
+        let info = json!({
+            "id": id,
+            "original_path": original_path.to_string_lossy(),
+            "deleted_at": deleted_at.to_rfc3339(),
+        });
+        fs::write(self.info_path(&id), serde_json::to_string_pretty(&info)?)?;
+
+        if let Err(e) = move_into(path, &self.files_dir().join(&id)) {
+            let _ = fs::remove_file(self.info_path(&id));
+            return Err(e.context(format!("Failed to move '{}' to trash", path.display())));
+        }
+
+        Ok(id)
+    }
+
+    fn next_id(&self, deleted_at: &DateTime<Local>) -> String {
+        let base = deleted_at.format("%Y%m%d-%H%M%S").to_string();
+        let mut id = base.clone();
+        let mut counter = 1;
+
+        while self.info_path(&id).exists() || self.files_dir().join(&id).exists() {
+            id = format!("{}-{}", base, counter);
+            counter += 1;
+        }
+        id
+    }
+
+    /// Whether `id` is still in the trash.
+    pub fn contains(&self, id: &str) -> bool {
+        check_id(id).is_ok() && self.info_path(id).exists()
+    }
+
+    /// Returns every trashed item, oldest first.
+    pub fn list(&self) -> anyhow::Result<Vec<TrashEntry>> {
+        let mut entries = Vec::new();
+
+        let dir = match fs::read_dir(self.info_dir()) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in dir {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                entries.push(read_info(&path)?);
+            }
+        }
+
+        entries.sort_by(|a, b| a.deleted_at.cmp(&b.deleted_at).then(a.id.cmp(&b.id)));
+        Ok(entries)
+    }
+
+    /// Moves a trashed item back to where it was deleted from.
+    pub fn restore(&self, id: &str) -> anyhow::Result<PathBuf> {
+        check_id(id)?;
+        let info_path = self.info_path(id);
+        if !info_path.exists() {
+            return Err(anyhow!("Error: No trash entry with id '{}'.", id));
+        }
+
+        let entry = read_info(&info_path)?;
+        if fs::symlink_metadata(&entry.original_path).is_ok() {
+            return Err(anyhow!(
+                "Error: Cannot restore '{}': path already exists.",
+                entry.original_path.display()
+            ));
+        }
+
+        if let Some(parent) = entry.original_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        move_into(&self.files_dir().join(id), &entry.original_path)?;
+        fs::remove_file(info_path)?;
+        Ok(entry.original_path)
+    }
+
+    /// Permanently deletes trashed items, only those older than `older_than`
+    /// when given. Returns how many items were deleted.
+    pub fn empty(&self, older_than: Option<Duration>) -> anyhow::Result<usize> {
+        let now = Local::now();
+        let mut removed = 0;
+
+        for entry in self.list()? {
+            if let Some(age) = older_than {
+                if now - entry.deleted_at < age {
+                    continue;
+                }
+            }
+
+            let payload = self.files_dir().join(&entry.id);
+            match fs::symlink_metadata(&payload) {
+                Ok(md) if md.is_dir() => fs::remove_dir_all(&payload)?,
+                Ok(_) => fs::remove_file(&payload)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            fs::remove_file(self.info_path(&entry.id))?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Where the tool keeps its per-user data: `$XDG_DATA_HOME/clap-tool-file`,
+/// or `~/.local/share/clap-tool-file`.
+pub fn data_dir() -> anyhow::Result<PathBuf> {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".local").join("share"))
+            .ok_or_else(|| anyhow!("Error: Cannot locate the home directory."))?,
+    };
+    Ok(data_dir.join("clap-tool-file"))
+}
+
+pub fn run_trash(args: &TrashArgs) -> anyhow::Result<()> {
+    let trash = Trash::open_default()?;
+
+    match &args.command {
+        TrashCommands::List => {
+            let entries = trash.list()?;
+            if entries.is_empty() {
+                println!("Trash is empty.");
+                return Ok(());
+            }
+
+            println!("{:<20} {:<20} Original path", "Id", "Deleted at");
+            println!("{:-<20} {:-<20} {:-<13}", "", "", "");
+            for entry in entries {
+                println!(
+                    "{:<20} {:<20} {}",
+                    entry.id,
+                    entry.deleted_at.format("%Y-%m-%d %H:%M:%S"),
+                    entry.original_path.display()
+                );
+            }
+        }
+        TrashCommands::Restore { id } => {
+            let restored = trash.restore(id)?;
+            println!("Restored '{}'", restored.display());
+        }
+        TrashCommands::Empty { older_than } => {
+            let older_than = older_than.as_deref().map(parse_age).transpose()?;
+            let removed = trash.empty(older_than)?;
+            println!("Permanently removed {} item(s) from trash", removed);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that `id` is a plain name, so joining it to a directory cannot
+/// point outside of it.
+pub fn check_id(id: &str) -> anyhow::Result<()> {
+    let mut components = Path::new(id).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name == id && !id.contains(['/', '\\']) => Ok(()),
+        _ => Err(anyhow!("Error: Invalid id '{}'.", id)),
+    }
+}
+
+/// Parses ages such as `30s`, `15m`, `12h`, `7d` or `2w`.
+pub fn parse_age(input: &str) -> anyhow::Result<Duration> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (value, unit) = input.split_at(split);
+
+    let value: i64 = value
+        .parse()
+        .with_context(|| format!("Invalid age '{}'. Example: 7d", input))?;
+
+    match unit {
+        "s" => Ok(Duration::seconds(value)),
+        "m" => Ok(Duration::minutes(value)),
+        "h" => Ok(Duration::hours(value)),
+        "" | "d" => Ok(Duration::days(value)),
+        "w" => Ok(Duration::weeks(value)),
+        _ => Err(anyhow!("Invalid age unit '{}'. Use s, m, h, d or w.", unit)),
+    }
+}
+
+fn read_info(path: &Path) -> anyhow::Result<TrashEntry> {
+    let contents = fs::read_to_string(path)?;
+    let info: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("Corrupt trash info {}", path.display()))?;
+
+    let field = |name: &str| {
+        info[name]
+            .as_str()
+            .ok_or_else(|| anyhow!("Trash info {} is missing '{}'", path.display(), name))
+    };
+
+    let id = field("id")?;
+    check_id(id).with_context(|| format!("Corrupt trash info {}", path.display()))?;
+
+    Ok(TrashEntry {
+        id: id.to_string(),
+        original_path: PathBuf::from(field("original_path")?),
+        deleted_at: DateTime::parse_from_rfc3339(field("deleted_at")?)?.with_timezone(&Local),
+    })
+}
+
+fn move_into(src: &Path, dest: &Path) -> anyhow::Result<()> {
+    match fs::rename(src, dest) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => move_by_copy(src, dest),
+        Err(e) => Err(e.into()),
+    }
+}